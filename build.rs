@@ -9,8 +9,10 @@ fn main() -> anyhow::Result<()> {
     // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
     // see also https://github.com/rust-lang/cargo/issues/9554
 
-    embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
-    embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf") {
+        embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
+        embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    }
 
     Ok(())
 }
@@ -4,45 +4,144 @@ use askama::Template;
 use axum::{
     Json, Router,
     body::Body,
-    extract::{Form, State},
+    extract::{Form, Query, State},
     http::StatusCode,
-    http::{Response, header},
+    http::{Response, Uri, header},
+    middleware,
     response::Html,
     response::IntoResponse,
     routing::*,
 };
 pub use axum_macros::debug_handler;
-use embedded_svc::http::client::Client as HttpClient;
-use esp_idf_svc::{http::client::EspHttpConnection, io, ota::EspOta};
 use std::any::Any;
 
 use crate::*;
 
 pub async fn run_api_server(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    let mut config = state.config.read().await.clone();
+    if state.safe_mode {
+        info!("Safe mode, HTTPS disabled.");
+        config.https_enabled = false;
+    }
+
     loop {
-        if *state.wifi_up.read().await {
+        if *state.wifi_up.read().await || *state.ap_mode.read().await {
             break;
         }
         sleep(Duration::from_secs(1)).await;
     }
 
-    let listen = format!("0.0.0.0:{}", DEFAULT_API_PORT);
-    let addr = listen.parse::<net::SocketAddr>()?;
+    if state.safe_mode || *state.ap_mode.read().await {
+        // always serve plain HTTP, so that the device can be recovered or provisioned
+        info!("Serving the API over plain HTTP.");
+        config.web_ui_enabled = true;
+        config.http_disabled = false;
+        config.http_redirect = false;
+    }
+    if !config.web_ui_enabled {
+        info!("Web UI and API disabled.");
+        std::future::pending::<()>().await;
+    }
 
-    let app = Router::new()
+    let protected = Router::new()
         .route("/", get(get_index))
+        .route("/passwd", post(set_passwd).options(options))
+        .route(
+            "/conf",
+            get(get_config)
+                .post(set_config)
+                .patch(patch_config)
+                .options(options),
+        )
+        .route("/conf/export", get(export_config))
+        .route("/conf/import", post(import_config).options(options))
+        .route("/reset_conf", get(reset_config))
+        .route("/fw", post(update_fw).options(options))
+        .route("/tls", post(set_tls_cert).options(options))
+        .route("/wifi/scan", get(scan_wifi))
+        .route("/wifi/events", get(get_wifi_events))
+        .route("/wifi/certs", post(set_eap_certs).options(options))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_auth));
+
+    let app = Router::new()
         .route("/favicon.ico", get(get_favicon))
         .route("/form.js", get(get_formjs))
         .route("/index.css", get(get_indexcss))
-        .route("/conf", get(get_config).post(set_config).options(options))
-        .route("/reset_conf", get(reset_config))
-        .route("/fw", post(update_fw).options(options))
-        .with_state(state);
+        .route("/status", get(get_status))
+        .route("/version", get(get_version))
+        .merge(protected)
+        .fallback(captive_redirect)
+        .with_state(state.clone());
     // .layer(TraceLayer::new_for_http());
 
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    info!("API server listening to {listen}");
-    Ok(axum::serve(listener, app.into_make_service()).await?)
+    // the HTTPS server terminates TLS and forwards plain HTTP to this loopback listener
+    let proxied = async {
+        if !config.https_enabled {
+            std::future::pending::<()>().await;
+        }
+        let listen = format!("127.0.0.1:{HTTPS_PROXY_PORT}");
+        let listener = tokio::net::TcpListener::bind(&listen).await?;
+        info!("API server listening to {listen} for HTTPS");
+        anyhow::Ok(axum::serve(listener, app.clone().into_make_service()).await?)
+    };
+
+    let plain = async {
+        let app = match (
+            config.https_enabled,
+            config.http_disabled,
+            config.http_redirect,
+        ) {
+            (true, true, _) => {
+                info!("Plain HTTP disabled.");
+                std::future::pending().await
+            }
+            (true, false, true) => Router::new()
+                .fallback(redirect_https)
+                .with_state(state.clone()),
+            _ => app.clone(),
+        };
+        let listen = format!("0.0.0.0:{}", config.api_port);
+        let listener = tokio::net::TcpListener::bind(&listen).await?;
+        info!("API server listening to {listen}");
+        anyhow::Ok(axum::serve(listener, app.into_make_service()).await?)
+    };
+
+    tokio::try_join!(Box::pin(proxied), Box::pin(plain))?;
+    Ok(())
+}
+
+pub async fn redirect_https(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    headers: header::HeaderMap,
+    uri: Uri,
+) -> Response<Body> {
+    let host = match headers.get(header::HOST).and_then(|h| h.to_str().ok()) {
+        Some(h) => h.split(':').next().unwrap_or(h).to_string(),
+        None => state.ip_addr.read().await.to_string(),
+    };
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = match state.config.read().await.https_port {
+        DEFAULT_HTTPS_PORT => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    };
+
+    (
+        StatusCode::TEMPORARY_REDIRECT,
+        [(header::LOCATION, location)],
+    )
+        .into_response()
+}
+
+/// Send access point clients probing for connectivity to the config UI.
+pub async fn captive_redirect(State(state): State<Arc<Pin<Box<MyState>>>>) -> Response<Body> {
+    if !*state.ap_mode.read().await {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let location = match state.config.read().await.api_port {
+        DEFAULT_API_PORT => format!("http://{WIFI_AP_IP}/"),
+        port => format!("http://{WIFI_AP_IP}:{port}/"),
+    };
+    (StatusCode::FOUND, [(header::LOCATION, location)]).into_response()
 }
 
 pub async fn options(State(state): State<Arc<Pin<Box<MyState>>>>) -> Response<Body> {
@@ -53,8 +152,11 @@ pub async fn options(State(state): State<Arc<Pin<Box<MyState>>>>) -> Response<Bo
         StatusCode::OK,
         [
             (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
-            (header::ACCESS_CONTROL_ALLOW_METHODS, "get,post"),
-            (header::ACCESS_CONTROL_ALLOW_HEADERS, "content-type"),
+            (header::ACCESS_CONTROL_ALLOW_METHODS, "get,post,patch"),
+            (
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                "content-type,authorization",
+            ),
         ],
     )
         .into_response()
@@ -64,8 +166,16 @@ pub async fn get_index(State(state): State<Arc<Pin<Box<MyState>>>>) -> Response<
     let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
     info!("#{cnt} get_index()");
 
-    let value_tuple: (&str, &dyn Any) = ("ota_slot", &state.ota_slot.clone());
-    let index = match state.config.clone().render_with_values(&value_tuple) {
+    let status = MyStatus::collect(&state).await;
+    let version = MyVersion::get();
+    let values: [(&str, &dyn Any); 4] = [
+        ("ota_slot", &state.ota_slot.clone()),
+        ("safe_mode", &state.safe_mode),
+        ("status", &status),
+        ("version", &version),
+    ];
+    let config = state.config.read().await.masked();
+    let index = match config.render_with_values(&values) {
         Err(e) => {
             let err_msg = format!("Index template error: {e:?}\n");
             error!("{err_msg}");
@@ -114,26 +224,155 @@ pub async fn get_indexcss(State(state): State<Arc<Pin<Box<MyState>>>>) -> Respon
     )
         .into_response()
 }
+
+pub async fn get_status(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+) -> (StatusCode, Json<MyStatus>) {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} get_status()");
+
+    (StatusCode::OK, Json(MyStatus::collect(&state).await))
+}
+
+pub async fn scan_wifi(State(state): State<Arc<Pin<Box<MyState>>>>) -> Response<Body> {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} scan_wifi()");
+
+    // the wifi loop owns the driver, ask it to scan and wait for the result;
+    // a busy loop scans on its own when connecting, so no request is kept for later
+    let done = state.wifi_scan_done.notified();
+    state.wifi_scan_req.notify_waiters();
+    if tokio::time::timeout(WIFI_SCAN_TIMEOUT, done).await.is_err() {
+        error!("WiFi scan timed out");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "WiFi is busy connecting, try again later",
+        )
+            .into_response();
+    }
+
+    (StatusCode::OK, Json(state.wifi_scan.read().await.clone())).into_response()
+}
+
+pub async fn get_wifi_events(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+) -> (StatusCode, Json<Vec<WifiEventEntry>>) {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} get_wifi_events()");
+
+    let events = state.wifi_events.read().await;
+    (StatusCode::OK, Json(events.iter().cloned().collect()))
+}
+
+pub async fn get_version(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+) -> (StatusCode, Json<MyVersion>) {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} get_version()");
+
+    (StatusCode::OK, Json(MyVersion::get()))
+}
+
 pub async fn get_config(
     State(state): State<Arc<Pin<Box<MyState>>>>,
 ) -> (StatusCode, Json<MyConfig>) {
     let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
     info!("#{cnt} get_conf()");
 
-    (StatusCode::OK, Json(state.config.clone()))
+    (StatusCode::OK, Json(state.config.read().await.masked()))
+}
+
+pub async fn export_config(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    Query(params): Query<ExportParams>,
+) -> Response<Body> {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} export_conf()");
+
+    let export = match state.config.read().await.export(params.secrets) {
+        Ok(e) => e,
+        Err(e) => {
+            let msg = format!("Config export error: {e:?}");
+            error!("{msg}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
+        }
+    };
+    let disposition = format!(
+        "attachment; filename=\"{}-config.json\"",
+        state.myid.read().await
+    );
+    (
+        StatusCode::OK,
+        [(header::CONTENT_DISPOSITION, disposition)],
+        Json(export),
+    )
+        .into_response()
+}
+
+pub async fn import_config(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    Json(export): Json<ConfigExport>,
+) -> Response<Body> {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} import_conf()");
+
+    info!(
+        "Importing config from firmware {} ({}), schema version {}",
+        export.fw_version, export.hw_feature, export.schema_version
+    );
+    let config = match MyConfig::from_export(export) {
+        Ok(c) => c,
+        Err(msg) => {
+            error!("{msg}");
+            return (StatusCode::BAD_REQUEST, msg).into_response();
+        }
+    };
+
+    Box::pin(apply_config(state, config)).await
 }
 
 pub async fn set_config(
     State(state): State<Arc<Pin<Box<MyState>>>>,
-    Json(mut config): Json<MyConfig>,
-) -> (StatusCode, String) {
+    Json(config): Json<MyConfig>,
+) -> Response<Body> {
     let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
     info!("#{cnt} set_conf()");
 
-    if config.v4mask > 30 {
-        let msg = "IPv4 mask error: bits must be between 0..30";
-        error!("{}", msg);
-        return (StatusCode::INTERNAL_SERVER_ERROR, msg.to_string());
+    Box::pin(apply_config(state, config)).await
+}
+
+pub async fn patch_config(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    Json(patch): Json<serde_json::Value>,
+) -> Response<Body> {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} patch_conf()");
+
+    let merged = state.config.read().await.merged(patch);
+    let config = match merged {
+        Ok(c) => c,
+        Err(msg) => {
+            error!("{msg}");
+            return (StatusCode::BAD_REQUEST, msg).into_response();
+        }
+    };
+
+    Box::pin(apply_config(state, config)).await
+}
+
+async fn apply_config(state: Arc<Pin<Box<MyState>>>, mut config: MyConfig) -> Response<Body> {
+    // the admin password can only be changed via /passwd
+    {
+        let current = state.config.read().await;
+        config.admin_pass = current.admin_pass.clone();
+        config.unmask(&current);
+    }
+
+    if let Err(errors) = config.validate() {
+        for e in errors.iter() {
+            error!("Config error: {}: {}", e.field, e.message);
+        }
+        return (StatusCode::BAD_REQUEST, Json(errors)).into_response();
     }
 
     if config.v4dhcp {
@@ -149,31 +388,203 @@ pub async fn set_config(
     Box::pin(save_config(state, config)).await
 }
 
-pub async fn reset_config(State(state): State<Arc<Pin<Box<MyState>>>>) -> (StatusCode, String) {
+pub async fn reset_config(State(state): State<Arc<Pin<Box<MyState>>>>) -> Response<Body> {
     let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
     info!("#{cnt} reset_conf()");
 
+    // keep the admin password, otherwise anyone could claim the device afterwards
+    let config = MyConfig {
+        admin_pass: state.config.read().await.admin_pass.clone(),
+        ..Default::default()
+    };
+
     info!("Saving  default config to nvs...");
-    Box::pin(save_config(state, MyConfig::default())).await
+    Box::pin(save_config(state, config)).await
+}
+
+pub async fn set_passwd(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    Json(passwd): Json<SetPassword>,
+) -> Response<Body> {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} set_passwd()");
+
+    if passwd.password.len() < ADMIN_PASS_MIN_LEN {
+        let msg = format!("Admin password must be at least {ADMIN_PASS_MIN_LEN} characters");
+        error!("{msg}");
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    let res = task::spawn_blocking(move || hash_password(&passwd.password)).await;
+    let admin_pass = match res.map_err(anyhow::Error::from).and_then(|r| r) {
+        Ok(h) => h,
+        Err(e) => {
+            let msg = format!("Password hashing failed: {e:?}");
+            error!("{msg}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
+        }
+    };
+    let config = MyConfig {
+        admin_pass,
+        ..state.config.read().await.clone()
+    };
+
+    info!("Saving new admin password to nvs...");
+    Box::pin(save_config(state, config)).await
+}
+
+async fn save_config(state: Arc<Pin<Box<MyState>>>, config: MyConfig) -> Response<Body> {
+    let mut nvs = state.nvs.write().await;
+    // the running config is stale until then, a merge onto it would drop the pending save
+    if *state.restart.read().await {
+        let msg = "A restart is pending, try again after it";
+        error!("{msg}");
+        return (StatusCode::SERVICE_UNAVAILABLE, msg).into_response();
+    }
+    if let Err(e) = config.to_nvs(&mut nvs) {
+        let (status, msg) = match e.downcast_ref::<ConfigStoreError>() {
+            Some(e @ ConfigStoreError::TooLarge(_)) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+            }
+            Some(e @ ConfigStoreError::NvsFull) => {
+                (StatusCode::INSUFFICIENT_STORAGE, e.to_string())
+            }
+            None => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Nvs write error: {e:?}"),
+            ),
+        };
+        error!("{}", msg);
+        return (status, msg).into_response();
+    }
+
+    let mut current = state.config.write().await;
+    // leaving safe mode always takes a restart
+    let restart = state.safe_mode || !current.is_hot_change(&config);
+    if state.safe_mode {
+        if let Err(e) = clear_boot_count(&mut nvs) {
+            error!("Nvs write error: {e:?}");
+        }
+    }
+    if restart {
+        info!("Config saved to nvs. Restarting soon...");
+        // schedule a restart
+        *state.restart.write().await = true;
+    } else {
+        info!("Config saved to nvs. Applying serial settings...");
+        *current = config;
+        state.serial_reconfig.notify_one();
+        state.mdns_refresh.notify_one();
+    }
+    (StatusCode::OK, Json(ConfigSaved { restart })).into_response()
+}
+
+pub async fn set_tls_cert(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    Json(upload): Json<UploadTlsCert>,
+) -> (StatusCode, String) {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} set_tls_cert()");
+
+    if upload.cert.trim().len() > CERT_PEM_MAX || upload.key.trim().len() > CERT_PEM_MAX {
+        let msg = format!("TLS certificate and key are limited to {CERT_PEM_MAX} bytes each");
+        error!("{msg}");
+        return (StatusCode::PAYLOAD_TOO_LARGE, msg);
+    }
+
+    let mut nvs = state.nvs.write().await;
+    // an empty upload reverts to a self-signed certificate generated on next boot
+    let res = if upload.cert.is_empty() && upload.key.is_empty() {
+        TlsCert::remove_nvs(&mut nvs)
+    } else {
+        let cert = match TlsCert::new(&upload.cert, &upload.key) {
+            Ok(c) => c,
+            Err(e) => {
+                let msg = format!("Invalid TLS certificate: {e:?}");
+                error!("{msg}");
+                return (StatusCode::BAD_REQUEST, msg);
+            }
+        };
+        if let Err(e) = check_nvs_space(cert.fits_nvs(), "TLS certificate") {
+            return e;
+        }
+        cert.to_nvs(&mut nvs)
+    };
+
+    match res {
+        Ok(_) => {
+            info!("TLS certificate updated. Restarting soon...");
+            *state.restart.write().await = true;
+            (StatusCode::OK, "OK".to_string())
+        }
+        Err(e) => {
+            let msg = format!("Nvs write error: {e:?}");
+            error!("{msg}");
+            (StatusCode::INTERNAL_SERVER_ERROR, msg)
+        }
+    }
 }
 
-async fn save_config(state: Arc<Pin<Box<MyState>>>, config: MyConfig) -> (StatusCode, String) {
+pub async fn set_eap_certs(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    Json(upload): Json<UploadEapCerts>,
+) -> (StatusCode, String) {
+    let cnt = state.api_cnt.fetch_add(1, Ordering::Relaxed);
+    info!("#{cnt} set_eap_certs()");
+
+    if [&upload.ca, &upload.cert, &upload.key]
+        .iter()
+        .any(|pem| pem.trim().len() > CERT_PEM_MAX)
+    {
+        let msg = format!("EAP certificates and key are limited to {CERT_PEM_MAX} bytes each");
+        error!("{msg}");
+        return (StatusCode::PAYLOAD_TOO_LARGE, msg);
+    }
+
+    // the upload replaces all of them, an empty one removes them
+    let certs = match EapCerts::new(&upload) {
+        Ok(c) => c,
+        Err(e) => {
+            let msg = format!("Invalid EAP certificate: {e:?}");
+            error!("{msg}");
+            return (StatusCode::BAD_REQUEST, msg);
+        }
+    };
+
     let mut nvs = state.nvs.write().await;
-    match config.to_nvs(&mut nvs) {
+    if let Err(e) = check_nvs_space(certs.fits_nvs(), "EAP certificates") {
+        return e;
+    }
+    match certs.to_nvs(&mut nvs) {
         Ok(_) => {
-            info!("Config saved to nvs. Restarting soon...");
-            // schedule a restart
+            info!("EAP certificates updated. Restarting soon...");
             *state.restart.write().await = true;
             (StatusCode::OK, "OK".to_string())
         }
         Err(e) => {
             let msg = format!("Nvs write error: {e:?}");
-            error!("{}", msg);
+            error!("{msg}");
             (StatusCode::INTERNAL_SERVER_ERROR, msg)
         }
     }
 }
 
+fn check_nvs_space(fits: anyhow::Result<bool>, what: &str) -> Result<(), (StatusCode, String)> {
+    let (status, msg) = match fits {
+        Ok(true) => return Ok(()),
+        Ok(false) => (
+            StatusCode::INSUFFICIENT_STORAGE,
+            format!("Not enough space left in nvs for the {what}"),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Nvs stats error: {e:?}"),
+        ),
+    };
+    error!("{msg}");
+    Err((status, msg))
+}
+
 async fn update_fw(
     State(state): State<Arc<Pin<Box<MyState>>>>,
     Form(fw_update): Form<UpdateFirmware>,
@@ -182,25 +593,15 @@ async fn update_fw(
     info!("#{cnt} update_fw()");
 
     info!("Firmware update: \n{fw_update:#?}");
-    let url = fw_update.url.to_owned();
-
-    let mut ota = EspOta::new().unwrap();
-    let mut client = HttpClient::wrap(EspHttpConnection::new(&Default::default()).unwrap());
-    let req = client.get(&url).unwrap();
-    let resp = req.submit().unwrap();
-    if resp.status() != StatusCode::OK {
-        return StatusCode::from_u16(resp.status()).unwrap().into_response();
+    let res = task::spawn_blocking(move || install_fw(&fw_update.url, None)).await;
+    if let Err(e) = res.map_err(anyhow::Error::from).and_then(|r| r) {
+        let msg = format!("Firmware update failed: {e:?}");
+        error!("{msg}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
     }
 
-    let update_src = Box::new(resp);
-    let mut update = ota.initiate_update().unwrap();
-    let mut buffer = [0_u8; 8192];
-
-    io::utils::copy(update_src, &mut update, &mut buffer).unwrap();
-    update.complete().unwrap();
-
     info!("Update done. Restarting...");
-    esp_idf_svc::hal::reset::restart();
+    requested_restart(&state).await;
 
     // not reached
     // StatusCode::OK.into_response()
@@ -0,0 +1,249 @@
+// auth.rs
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{Method, Response, StatusCode, header},
+    middleware::Next,
+    response::IntoResponse,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use sha2::{Digest, Sha256};
+
+use crate::*;
+
+pub const ADMIN_USER: &str = "admin";
+pub const ADMIN_PASS_MIN_LEN: usize = 8;
+const SALT_LEN: usize = 16;
+const DIGEST_LEN: usize = 32;
+const PBKDF2_PREFIX: &str = "pbkdf2";
+// slow on purpose, verified logins are cached in MyState::auth_ok
+const PBKDF2_ITERATIONS: u32 = 10_000;
+
+// These are reachable without credentials until the admin password has been set
+const SETUP_PATHS: [&str; 2] = ["/", "/passwd"];
+
+// not in the esp-idf-sys bindings, but linked in with mbedtls
+#[cfg(target_os = "espidf")]
+unsafe extern "C" {
+    fn mbedtls_pkcs5_pbkdf2_hmac_ext(
+        md_type: esp_idf_sys::mbedtls_md_type_t,
+        password: *const u8,
+        plen: usize,
+        salt: *const u8,
+        slen: usize,
+        iteration_count: core::ffi::c_uint,
+        key_length: u32,
+        output: *mut u8,
+    ) -> core::ffi::c_int;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPassword {
+    pub password: String,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(target_os = "espidf")]
+fn pbkdf2_digest(salt: &str, password: &str, iterations: u32) -> anyhow::Result<String> {
+    let mut digest = [0u8; DIGEST_LEN];
+    let rc = unsafe {
+        mbedtls_pkcs5_pbkdf2_hmac_ext(
+            esp_idf_sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+            password.as_ptr(),
+            password.len(),
+            salt.as_ptr(),
+            salt.len(),
+            iterations,
+            digest.len() as u32,
+            digest.as_mut_ptr(),
+        )
+    };
+    if rc != 0 {
+        bail!("PBKDF2 failed: {rc}");
+    }
+    Ok(to_hex(&digest))
+}
+
+/// Key for the last verified login in `MyState::auth_ok`, never stored in config.
+fn auth_cache_key(stored: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(stored.as_bytes());
+    hasher.update(password.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+// compare in constant time
+fn same_digest(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Split a stored hash into iterations, salt and digest.
+fn parse_hash(stored: &str) -> Option<(u32, &str, &str)> {
+    let mut parts = stored.split('$');
+    if parts.next()? != PBKDF2_PREFIX {
+        return None;
+    }
+    let iterations = parts.next()?.parse().ok().filter(|&i| i > 0)?;
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(salt), Some(digest), None) => Some((iterations, salt, digest)),
+        _ => None,
+    }
+}
+
+/// Hash a password for storing in config, as `pbkdf2$<iterations>$<salt>$<digest>` in hex.
+#[cfg(target_os = "espidf")]
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    unsafe {
+        esp_idf_sys::esp_fill_random(salt.as_mut_ptr() as *mut core::ffi::c_void, salt.len());
+    }
+    let salt = to_hex(&salt);
+    let digest = pbkdf2_digest(&salt, password, PBKDF2_ITERATIONS)?;
+    Ok(format!(
+        "{PBKDF2_PREFIX}${PBKDF2_ITERATIONS}${salt}${digest}"
+    ))
+}
+
+/// Check a password against a hash from `hash_password()`.
+#[cfg(target_os = "espidf")]
+pub fn verify_password(stored: &str, password: &str) -> bool {
+    let Some((iterations, salt, digest)) = parse_hash(stored) else {
+        return false;
+    };
+    match pbkdf2_digest(salt, password, iterations) {
+        Ok(candidate) => same_digest(&candidate, digest),
+        Err(e) => {
+            error!("{e:?}");
+            false
+        }
+    }
+}
+
+/// The password from an Authorization header, if it is for the admin user.
+fn credentials(auth: &str) -> Option<String> {
+    if let Some(token) = auth.strip_prefix("Bearer ") {
+        return Some(token.trim().to_string());
+    }
+    let encoded = auth.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    match decoded.split_once(':') {
+        Some((user, pass)) if user == ADMIN_USER => Some(pass.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "espidf")]
+async fn check_password(state: &MyState, stored: &str, password: String) -> bool {
+    // a fast digest of the last verified login saves running PBKDF2 on every request
+    let key = auth_cache_key(stored, &password);
+    let cached = state.auth_ok.read().await.clone();
+    if cached.is_some_and(|ok| same_digest(&ok, &key)) {
+        return true;
+    }
+
+    let stored = stored.to_string();
+    let res = task::spawn_blocking(move || verify_password(&stored, &password)).await;
+    let verified = res.unwrap_or_else(|e| {
+        error!("Password check failed: {e:?}");
+        false
+    });
+    if verified {
+        *state.auth_ok.write().await = Some(key);
+    }
+    verified
+}
+
+#[cfg(target_os = "espidf")]
+pub async fn require_auth(
+    State(state): State<Arc<Pin<Box<MyState>>>>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let stored = state.config.read().await.admin_pass.clone();
+
+    // CORS preflight requests never carry credentials
+    if req.method() == Method::OPTIONS {
+        return next.run(req).await;
+    }
+
+    if stored.is_empty() {
+        if SETUP_PATHS.contains(&req.uri().path()) {
+            return next.run(req).await;
+        }
+        let msg = "Admin password is not set, set it first via /passwd";
+        error!("{msg}");
+        return (StatusCode::FORBIDDEN, msg).into_response();
+    }
+
+    let password = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(credentials);
+    let authorized = match password {
+        Some(password) => Box::pin(check_password(&state, &stored, password)).await,
+        None => false,
+    };
+    if authorized {
+        return next.run(req).await;
+    }
+
+    info!("Unauthorized request to {}", req.uri().path());
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"esp32serial\"")],
+        "Unauthorized",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stored_hash() {
+        assert_eq!(
+            parse_hash("pbkdf2$10000$00ff$abcd"),
+            Some((10000, "00ff", "abcd"))
+        );
+        assert_eq!(parse_hash("00ff$abcd"), None);
+        assert_eq!(parse_hash("pbkdf2$0$00ff$abcd"), None);
+        assert_eq!(parse_hash("pbkdf2$many$00ff$abcd"), None);
+        assert_eq!(parse_hash("pbkdf2$10000$00ff"), None);
+        assert_eq!(parse_hash("pbkdf2$10000$00ff$abcd$"), None);
+        assert_eq!(parse_hash(""), None);
+    }
+
+    #[test]
+    fn unprefixed_hash_rejected() {
+        // plain salted SHA-256, as in the auth cache
+        let salt = "00ff00ff00ff00ff";
+        let legacy = format!("{salt}${}", auth_cache_key(salt, "hunter22"));
+        assert_eq!(parse_hash(&legacy), None);
+        assert_eq!(parse_hash(&format!("10000${salt}$abcd")), None);
+        assert_eq!(parse_hash(&format!("sha256$10000${salt}$abcd")), None);
+        assert_eq!(parse_hash(&format!("PBKDF2$10000${salt}$abcd")), None);
+    }
+
+    #[test]
+    fn credentials_from_header() {
+        assert_eq!(credentials("Bearer secret1 ").as_deref(), Some("secret1"));
+        let basic = format!("Basic {}", BASE64.encode("admin:pa:ss"));
+        assert_eq!(credentials(&basic).as_deref(), Some("pa:ss"));
+        let other = format!("Basic {}", BASE64.encode("root:pass"));
+        assert_eq!(credentials(&other), None);
+        assert_eq!(credentials("Basic !!"), None);
+        assert_eq!(credentials("Digest x"), None);
+    }
+}
+
+// EOF
@@ -8,8 +8,7 @@ use esp_idf_hal::{
     peripherals::Peripherals,
 };
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop, nvs, ota::EspOta, ping, timer::EspTaskTimerService,
-    wifi::WifiDriver,
+    eventloop::EspSystemEventLoop, nvs, ota::EspOta, timer::EspTaskTimerService, wifi::WifiDriver,
 };
 use esp_idf_sys::esp;
 
@@ -24,6 +23,9 @@ compile_error!("Select a hardware feature: `esp32-c3` or `esp-wroom-32`");
 // esp_app_desc!();
 
 const CONFIG_RESET_COUNT: i32 = 9;
+// PBKDF2, DNS lookups, pings and OTA downloads run on these
+const BLOCKING_THREAD_STACK: usize = 16 * 1024;
+const BLOCKING_THREADS_MAX: usize = 4;
 
 fn main() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
@@ -79,6 +81,8 @@ fn main() -> anyhow::Result<()> {
         // using settings saved on nvs if we could find them
         Some(c) => c,
     };
+    let config = check_config_fallback(&mut nvs, config)?;
+    let safe_mode = count_boot(&mut nvs)?;
     info!("My config:\n{config:#?}");
 
     let peripherals = Peripherals::take().unwrap();
@@ -111,6 +115,7 @@ fn main() -> anyhow::Result<()> {
     let state = Box::pin(MyState::new(
         config,
         ota_slot,
+        safe_mode,
         nvs,
         MySerial { uart, tx, rx, led },
     ));
@@ -118,20 +123,29 @@ fn main() -> anyhow::Result<()> {
 
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
+        .thread_stack_size(BLOCKING_THREAD_STACK)
+        .max_blocking_threads(BLOCKING_THREADS_MAX)
         .build()?
         .block_on(Box::pin(async move {
             let wifi_loop = WifiLoop {
                 state: shared_state.clone(),
                 wifi: None,
+                eap_certs: EapCerts::default(),
             };
 
             info!("Entering main loop...");
             tokio::select! {
                 _ = Box::pin(poll_reset(shared_state.clone(), button)) => { error!("poll_reset() ended."); }
                 _ = Box::pin(run_api_server(shared_state.clone())) => { error!("run_api_server() ended."); }
+                _ = Box::pin(run_https_server(shared_state.clone())) => { error!("run_https_server() ended."); }
                 _ = Box::pin(run_serial(shared_state.clone())) => { error!("run_serial() ended."); }
                 _ = Box::pin(wifi_loop.run(wifi_driver, sysloop, timer)) => { error!("wifi_loop() ended."); }
-                _ = Box::pin(pinger(shared_state.clone())) => { error!("pinger() ended."); }
+                _ = Box::pin(run_watchdog(shared_state.clone())) => { error!("run_watchdog() ended."); }
+                _ = Box::pin(run_fw_check(shared_state.clone())) => { error!("run_fw_check() ended."); }
+                _ = Box::pin(run_config_confirm(shared_state.clone())) => { error!("run_config_confirm() ended."); }
+                _ = Box::pin(run_boot_watch(shared_state.clone())) => { error!("run_boot_watch() ended."); }
+                _ = Box::pin(run_captive_dns(shared_state.clone())) => { error!("run_captive_dns() ended."); }
+                _ = Box::pin(run_mdns(shared_state.clone())) => { error!("run_mdns() ended."); }
 
             };
         }));
@@ -150,7 +164,7 @@ async fn poll_reset(
         sleep(Duration::from_secs(2)).await;
 
         if *state.restart.read().await {
-            esp_idf_hal::reset::restart();
+            requested_restart(&state).await;
         }
 
         if button.is_low() {
@@ -175,9 +189,12 @@ async fn reset_button<'a>(
             error!("Factory resetting...");
 
             let new_config = MyConfig::default();
-            new_config.to_nvs(&mut *state.nvs.write().await)?;
+            let mut nvs = state.nvs.write().await;
+            new_config.to_nvs(&mut nvs)?;
+            clear_config_fallback(&mut nvs)?;
+            drop(nvs);
             sleep(Duration::from_millis(2000)).await;
-            esp_idf_hal::reset::restart();
+            requested_restart(state).await;
         }
 
         reset_cnt -= 1;
@@ -187,34 +204,4 @@ async fn reset_button<'a>(
     Ok(())
 }
 
-async fn pinger(state: Arc<std::pin::Pin<Box<MyState>>>) -> anyhow::Result<()> {
-    loop {
-        sleep(Duration::from_secs(300)).await;
-
-        if let Some(ping_ip) = *state.ping_ip.read().await {
-            let if_idx = *state.if_index.read().await;
-            if if_idx > 0 {
-                tracing::log::info!("Starting ping {ping_ip} (if_idx {if_idx})");
-                let conf = ping::Configuration {
-                    count: 2,
-                    interval: Duration::from_millis(500),
-                    timeout: Duration::from_millis(200),
-                    data_size: 64,
-                    tos: 0,
-                };
-                let mut ping = ping::EspPing::new(if_idx);
-                let res = ping.ping(ping_ip, &conf)?;
-                tracing::log::info!("Pinger result: {res:?}");
-                if res.received == 0 {
-                    tracing::log::error!("Ping failed, rebooting.");
-                    sleep(Duration::from_millis(2000)).await;
-                    esp_idf_hal::reset::restart();
-                }
-            } else {
-                tracing::log::error!("No if_index. wat?");
-            }
-        }
-    }
-}
-
 // EOF
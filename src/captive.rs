@@ -0,0 +1,143 @@
+// captive.rs
+
+use crate::*;
+
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_LEN: usize = 12;
+const DNS_TTL: u32 = 60;
+
+/// Answer every DNS lookup from access point clients with our own address.
+#[cfg(target_os = "espidf")]
+pub async fn run_captive_dns(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    loop {
+        if *state.ap_mode.read().await {
+            break;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    let socket = tokio::net::UdpSocket::bind((WIFI_AP_IP, DNS_PORT)).await?;
+    info!("Captive portal DNS listening to {WIFI_AP_IP}:{DNS_PORT}");
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        if !*state.ap_mode.read().await {
+            continue;
+        }
+        if let Some(resp) = dns_answer(&buf[..n], WIFI_AP_IP) {
+            socket.send_to(&resp, peer).await?;
+        }
+    }
+}
+
+/// Response to a single question DNS query, with `addr` as the answer to an A question.
+pub fn dns_answer(query: &[u8], addr: net::Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    // QR and opcode must be zero
+    if flags & 0xf800 != 0 || qdcount != 1 {
+        return None;
+    }
+
+    // skip over the question name, compression is not used in queries
+    let mut pos = DNS_HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(pos + 2)?, *query.get(pos + 3)?]);
+    let is_a = qtype == 1 && qclass == 1;
+
+    // header and question, dropping any additional records of the query
+    let mut resp = query[..pos + 4].to_vec();
+    resp[2] = 0x84 | (query[2] & 0x01); // QR, AA, copy RD
+    resp[3] = 0x80; // RA, no error
+    resp[6..DNS_HEADER_LEN].copy_from_slice(&[0, is_a as u8, 0, 0, 0, 0]);
+    if is_a {
+        // pointer to the question name
+        resp.extend_from_slice(&[0xc0, DNS_HEADER_LEN as u8, 0, 1, 0, 1]);
+        resp.extend_from_slice(&DNS_TTL.to_be_bytes());
+        resp.extend_from_slice(&[0, 4]);
+        resp.extend_from_slice(&addr.octets());
+    }
+    Some(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: net::Ipv4Addr = net::Ipv4Addr::new(192, 168, 71, 1);
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&[0, 1]);
+        q
+    }
+
+    #[test]
+    fn answer_a_query() {
+        let q = query("connectivitycheck.gstatic.com", 1);
+        let resp = dns_answer(&q, ADDR).unwrap();
+        assert_eq!(resp[..2], [0x12, 0x34]);
+        assert_eq!(resp[2..4], [0x85, 0x80]);
+        assert_eq!(resp[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(resp[12..q.len()], q[12..]);
+        assert_eq!(
+            resp[q.len()..],
+            [0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]
+        );
+    }
+
+    #[test]
+    fn empty_answer_for_aaaa() {
+        let q = query("example.com", 28);
+        let resp = dns_answer(&q, ADDR).unwrap();
+        assert_eq!(resp[6..8], [0, 0]);
+        assert_eq!(resp.len(), q.len());
+    }
+
+    #[test]
+    fn drop_additional_records() {
+        let mut q = query("example.com", 1);
+        q[11] = 1;
+        let len = q.len();
+        // EDNS OPT record
+        q.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        let resp = dns_answer(&q, ADDR).unwrap();
+        assert_eq!(resp[10..12], [0, 0]);
+        assert_eq!(resp.len(), len + 16);
+    }
+
+    #[test]
+    fn ignore_invalid() {
+        assert_eq!(dns_answer(&[0; 5], ADDR), None);
+
+        let mut q = query("example.com", 1);
+        q[2] |= 0x80; // a response
+        assert_eq!(dns_answer(&q, ADDR), None);
+
+        let q = query("example.com", 1);
+        assert_eq!(dns_answer(&q[..q.len() - 2], ADDR), None);
+    }
+}
+
+// EOF
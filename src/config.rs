@@ -2,25 +2,49 @@
 
 use askama::Template;
 use crc::{CRC_32_ISCSI, Crc};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::nvs;
 
 use crate::*;
 
-pub const NVS_BUF_SIZE: usize = 256;
+// a config is kept in two keys, leave room in the nvs partition for the TLS and EAP certs
+pub const CONFIG_MAX_SIZE: usize = 2048;
 pub const BOOT_FAIL_MAX: u8 = 4;
+// a config on probation must prove the network in this time or the boot fails
+pub const CONFIG_CONFIRM_TIMEOUT: Duration = Duration::from_secs(300);
 pub const DEFAULT_API_PORT: u16 = 80;
+pub const DEFAULT_HTTPS_PORT: u16 = 443;
+/// Loopback-only HTTP port that the TLS proxy forwards decrypted requests to.
+pub const HTTPS_PROXY_PORT: u16 = 8080;
 const DEFAULT_SERIAL_TCP_PORT: u16 = 23;
+const DEFAULT_SERIAL_FRAMING: &str = "8N1";
+const DEFAULT_SERIAL_PKT_SIZE: u16 = 64;
+pub const SERIAL_PKT_SIZE_MAX: u16 = 1024;
+const DEFAULT_FW_CHECK_INTERVAL: u32 = 1440;
+const DEFAULT_WIFI_ROAM_RSSI: i8 = -75;
+const DEFAULT_WIFI_ROAM_PERIOD: u16 = 60;
+const DEFAULT_WATCHDOG_INTERVAL: u32 = 300;
 
 const CONFIG_NAME: &str = "cfg";
+const CONFIG_GOOD_NAME: &str = "cfg_good";
 
-#[derive(Clone, Debug, Serialize, Deserialize, Template)]
-#[template(path = "index.html.ask", escape = "html")]
+/// Placeholder shown instead of secrets in API output and the web UI.
+pub const SECRET_MASK: &str = "********";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(
+    target_os = "espidf",
+    derive(Template),
+    template(path = "index.html.ask", escape = "html")
+)]
 pub struct MyConfig {
-    pub wifi_ssid: String,
-    pub wifi_pass: String,
-    pub wifi_wpa2ent: bool,
-    pub wifi_username: String,
+    pub wifi_networks: Vec<WifiNetwork>,
+    pub wifi_roam_enabled: bool,
+    pub wifi_roam_rssi: i8,
+    pub wifi_roam_period: u16,
 
+    /// Station hostname for DHCP and DNS, empty for the device id.
+    pub hostname: String,
     pub v4dhcp: bool,
     pub v4addr: net::Ipv4Addr,
     pub v4mask: u8,
@@ -28,19 +52,202 @@ pub struct MyConfig {
     pub dns1: net::Ipv4Addr,
     pub dns2: net::Ipv4Addr,
 
+    pub api_port: u16,
+    pub web_ui_enabled: bool,
+
     pub bps: u32,
+    pub serial_framing: String,
     pub serial_tcp_port: u16,
     pub serial_write_enabled: bool,
+    pub serial_pkt_size: u16,
+    pub serial_pkt_timeout: u16,
+
+    pub fw_manifest_url: String,
+    pub fw_check_interval: u32,
+
+    #[serde(default)]
+    pub admin_pass: String,
+
+    pub https_enabled: bool,
+    pub https_port: u16,
+    pub http_redirect: bool,
+    pub http_disabled: bool,
+
+    pub watchdog_check: WatchdogCheck,
+    pub watchdog_targets: Vec<String>,
+    pub watchdog_interval: u32,
+    pub watchdog_failures: u8,
+    pub watchdog_action: WatchdogAction,
+}
+
+/// Outcome of `MyConfig::confirm_check()`.
+#[derive(Debug, PartialEq)]
+pub enum ConfigCheck {
+    Confirm,
+    Wait,
+    Fail,
+}
+
+/// How the connectivity watchdog tests its targets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogCheck {
+    Off,
+    /// ICMP echo to IPv4 addresses, the gateway if no targets are set.
+    #[default]
+    Ping,
+    /// TCP connect to `host:port` targets.
+    Tcp,
+    /// DNS lookup of host name targets.
+    Dns,
+}
+
+/// What the connectivity watchdog does once the checks keep failing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogAction {
+    Reconnect,
+    RestartNet,
+    #[default]
+    Reboot,
+}
+
+/// A known WiFi network. Higher priority networks are preferred when several are in range.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub pass: String,
+    pub wpa2ent: bool,
+    pub username: String,
+    pub priority: u8,
+    #[serde(default)]
+    pub eap_method: EapMethod,
+    #[serde(default)]
+    pub auth: WifiAuth,
+    #[serde(default)]
+    pub pmf: WifiPmf,
+    /// Access point to use, like `aa:bb:cc:dd:ee:ff`, empty for any.
+    #[serde(default)]
+    pub bssid: String,
+    /// Channel to look for the network on first, 0 for all.
+    #[serde(default)]
+    pub channel: u8,
+}
+
+impl WifiNetwork {
+    /// The pinned access point, if any.
+    pub fn parse_bssid(&self) -> Result<Option<[u8; 6]>, String> {
+        if self.bssid.is_empty() {
+            return Ok(None);
+        }
+        let err = || format!("BSSID {:?} is not like aa:bb:cc:dd:ee:ff", self.bssid);
+        let mut bssid = [0u8; 6];
+        let mut parts = self.bssid.split(':');
+        for b in bssid.iter_mut() {
+            let part = parts
+                .next()
+                .filter(|p| p.len() == 2 && p.bytes().all(|c| c.is_ascii_hexdigit()))
+                .ok_or_else(err)?;
+            *b = u8::from_str_radix(part, 16).map_err(|_| err())?;
+        }
+        match parts.next() {
+            Some(_) => Err(err()),
+            None => Ok(Some(bssid)),
+        }
+    }
+}
+
+/// Network fixture shared by the unit tests.
+#[cfg(test)]
+pub(crate) fn test_network(ssid: &str, pass: &str, priority: u8) -> WifiNetwork {
+    WifiNetwork {
+        ssid: ssid.into(),
+        pass: pass.into(),
+        priority,
+        ..Default::default()
+    }
+}
+
+/// Weakest security accepted from a personal network's access point.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiAuth {
+    /// Open without a password, otherwise WPA2 or better.
+    #[default]
+    Auto,
+    Wpa2,
+    /// WPA2/WPA3 transition mode or WPA3.
+    Wpa2Wpa3,
+    /// WPA3-SAE only, for networks that have disabled WPA2.
+    Wpa3,
+    Open,
+}
+
+/// Protected management frames, always offered and optionally insisted on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiPmf {
+    #[default]
+    Optional,
+    Required,
+}
+
+/// How an enterprise network authenticates us.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EapMethod {
+    /// PEAP or TTLS with username and password, whichever the server offers.
+    #[default]
+    Peap,
+    /// EAP-TLS with the uploaded client certificate.
+    Tls,
+}
+
+#[derive(Debug)]
+pub enum ConfigStoreError {
+    TooLarge(usize),
+    NvsFull,
+}
+
+impl std::fmt::Display for ConfigStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigStoreError::TooLarge(size) => write!(
+                f,
+                "Config needs {size} bytes, more than the limit of {CONFIG_MAX_SIZE} bytes"
+            ),
+            ConfigStoreError::NvsFull => write!(f, "Not enough space left in nvs for the config"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigStoreError {}
+
+/// Config backup as served by `/conf/export` and accepted by `/conf/import`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigExport {
+    pub fw_version: String,
+    pub schema_version: u8,
+    pub hw_feature: String,
+    pub config: serde_json::Value,
 }
 
 impl Default for MyConfig {
     fn default() -> Self {
         Self {
-            wifi_ssid: option_env!("WIFI_SSID").unwrap_or("internet").into(),
-            wifi_pass: option_env!("WIFI_PASS").unwrap_or("password").into(),
-            wifi_wpa2ent: false,
-            wifi_username: String::new(),
+            wifi_networks: match option_env!("WIFI_SSID") {
+                Some(ssid) if !ssid.is_empty() => vec![WifiNetwork {
+                    ssid: ssid.into(),
+                    pass: option_env!("WIFI_PASS").unwrap_or("").into(),
+                    ..Default::default()
+                }],
+                _ => vec![],
+            },
+            wifi_roam_enabled: false,
+            wifi_roam_rssi: DEFAULT_WIFI_ROAM_RSSI,
+            wifi_roam_period: DEFAULT_WIFI_ROAM_PERIOD,
 
+            hostname: String::new(),
             v4dhcp: true,
             v4addr: net::Ipv4Addr::new(0, 0, 0, 0),
             v4mask: 0,
@@ -48,65 +255,284 @@ impl Default for MyConfig {
             dns1: net::Ipv4Addr::new(0, 0, 0, 0),
             dns2: net::Ipv4Addr::new(0, 0, 0, 0),
 
+            api_port: DEFAULT_API_PORT,
+            web_ui_enabled: true,
+
             bps: 9600,
+            serial_framing: DEFAULT_SERIAL_FRAMING.into(),
             serial_tcp_port: DEFAULT_SERIAL_TCP_PORT,
             serial_write_enabled: true,
+            serial_pkt_size: DEFAULT_SERIAL_PKT_SIZE,
+            serial_pkt_timeout: 0,
+
+            fw_manifest_url: String::new(),
+            fw_check_interval: DEFAULT_FW_CHECK_INTERVAL,
+
+            admin_pass: String::new(),
+
+            https_enabled: false,
+            https_port: DEFAULT_HTTPS_PORT,
+            http_redirect: false,
+            http_disabled: false,
+
+            watchdog_check: WatchdogCheck::default(),
+            watchdog_targets: vec![],
+            watchdog_interval: DEFAULT_WATCHDOG_INTERVAL,
+            watchdog_failures: 1,
+            watchdog_action: WatchdogAction::default(),
         }
     }
 }
 
+impl MyConfig {
+    /// Copy of config that is safe to send over the wire, with secrets masked.
+    pub fn masked(&self) -> Self {
+        let mask = |s: &String| match s.is_empty() {
+            true => String::new(),
+            false => SECRET_MASK.into(),
+        };
+        Self {
+            wifi_networks: self
+                .wifi_networks
+                .iter()
+                .map(|n| WifiNetwork {
+                    pass: mask(&n.pass),
+                    ..n.clone()
+                })
+                .collect(),
+            admin_pass: mask(&self.admin_pass),
+            ..self.clone()
+        }
+    }
+
+    /// Data bits, parity (`N`, `E` or `O`) and stop bits parsed from framing like `8N1`.
+    pub fn parse_framing(&self) -> Result<(u8, char, u8), String> {
+        let err = || {
+            format!(
+                "Serial framing error: {:?} is not like 8N1",
+                self.serial_framing
+            )
+        };
+        let mut chars = self.serial_framing.trim().chars();
+        let (Some(d), Some(p), Some(s), None) =
+            (chars.next(), chars.next(), chars.next(), chars.next())
+        else {
+            return Err(err());
+        };
+
+        let data_bits = match d {
+            '5'..='8' => d as u8 - b'0',
+            _ => return Err(err()),
+        };
+        let parity = match p.to_ascii_uppercase() {
+            c @ ('N' | 'E' | 'O') => c,
+            _ => return Err(err()),
+        };
+        let stop_bits = match s {
+            '1' | '2' => s as u8 - b'0',
+            _ => return Err(err()),
+        };
+        Ok((data_bits, parity, stop_bits))
+    }
+
+    /// True if `new` differs from this config only in serial settings.
+    pub fn is_hot_change(&self, new: &MyConfig) -> bool {
+        let cold = MyConfig {
+            bps: self.bps,
+            serial_framing: self.serial_framing.clone(),
+            serial_write_enabled: self.serial_write_enabled,
+            serial_pkt_size: self.serial_pkt_size,
+            serial_pkt_timeout: self.serial_pkt_timeout,
+            ..new.clone()
+        };
+        cold == *self
+    }
+
+    /// Whether this config has proven the network, by DHCP or by a client reaching it.
+    pub fn confirm_check(&self, client_seen: bool, waited: Duration) -> ConfigCheck {
+        if self.v4dhcp || client_seen {
+            ConfigCheck::Confirm
+        } else if waited < CONFIG_CONFIRM_TIMEOUT {
+            ConfigCheck::Wait
+        } else {
+            ConfigCheck::Fail
+        }
+    }
+
+    /// Apply a JSON object with any subset of fields onto a copy of this config.
+    pub fn merged(&self, patch: serde_json::Value) -> Result<Self, String> {
+        let serde_json::Value::Object(patch) = patch else {
+            return Err("Config patch must be a JSON object".into());
+        };
+
+        let mut value =
+            serde_json::to_value(self).map_err(|e| format!("Cannot encode config: {e}"))?;
+        let Some(fields) = value.as_object_mut() else {
+            return Err("Cannot encode config as a JSON object".into());
+        };
+        for (name, v) in patch {
+            if !fields.contains_key(&name) {
+                return Err(format!("Unknown config field: {name}"));
+            }
+            fields.insert(name, v);
+        }
+
+        serde_json::from_value(value).map_err(|e| format!("Invalid config patch: {e}"))
+    }
+
+    /// Backup of this config with build metadata. The admin password is never included.
+    pub fn export(&self, secrets: bool) -> anyhow::Result<ConfigExport> {
+        let config = match secrets {
+            true => MyConfig {
+                admin_pass: self.masked().admin_pass,
+                ..self.clone()
+            },
+            false => self.masked(),
+        };
+        Ok(ConfigExport {
+            fw_version: FW_VERSION.into(),
+            schema_version: CONFIG_SCHEMA_VERSION,
+            hw_feature: HW_FEATURE.into(),
+            config: serde_json::to_value(config)?,
+        })
+    }
+
+    /// Config from a backup. Fields missing from backups of older firmware get their defaults.
+    pub fn from_export(mut export: ConfigExport) -> Result<Self, String> {
+        if export.schema_version > CONFIG_SCHEMA_VERSION {
+            return Err(format!(
+                "Backup schema version {} is newer than supported {CONFIG_SCHEMA_VERSION}",
+                export.schema_version
+            ));
+        }
+        upgrade_export(export.schema_version, &mut export.config);
+        MyConfig::default().merged(export.config)
+    }
+
+    /// Replace any secrets still set to the mask placeholder with the values from `current`.
+    pub fn unmask(&mut self, current: &MyConfig) {
+        for network in self.wifi_networks.iter_mut() {
+            if network.pass != SECRET_MASK {
+                continue;
+            }
+            if let Some(n) = current
+                .wifi_networks
+                .iter()
+                .find(|n| n.ssid == network.ssid)
+            {
+                network.pass = n.pass.clone();
+            }
+        }
+        if self.admin_pass == SECRET_MASK {
+            self.admin_pass = current.admin_pass.clone();
+        }
+    }
+
+    /// Decode a config blob of any known schema version, upgrading it as needed.
+    pub fn from_blob(blob: &[u8]) -> Result<Self, String> {
+        let (version, payload) = split_header(blob);
+        upgrade_config(version, payload)
+    }
+
+    /// Encode as a blob with the current schema version header.
+    pub fn to_blob(&self) -> anyhow::Result<Vec<u8>> {
+        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
+        let mut blob = vec![CONFIG_MAGIC, CONFIG_SCHEMA_VERSION];
+        match postcard::to_allocvec_crc32(self, crc.digest()) {
+            Ok(data) => blob.extend_from_slice(&data),
+            Err(e) => bail!("Cannot encode config {e:?}"),
+        }
+        if blob.len() > CONFIG_MAX_SIZE {
+            return Err(ConfigStoreError::TooLarge(blob.len()).into());
+        }
+        Ok(blob)
+    }
+}
+
+#[cfg(target_os = "espidf")]
 impl MyConfig {
     pub fn from_nvs(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> Option<Self> {
-        let mut nvsbuf = [0u8; NVS_BUF_SIZE];
-        info!("Reading up to {sz} bytes from nvs...", sz = NVS_BUF_SIZE);
-        let b = match nvs.get_blob(CONFIG_NAME, &mut nvsbuf) {
+        Self::from_nvs_key(nvs, CONFIG_NAME)
+    }
+
+    pub fn to_nvs(&self, nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        self.to_nvs_key(nvs, CONFIG_NAME)
+    }
+
+    /// The last config that brought up WiFi, kept for reverting a bad config.
+    pub fn good_from_nvs(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> Option<Self> {
+        Self::from_nvs_key(nvs, CONFIG_GOOD_NAME)
+    }
+
+    pub fn good_to_nvs(&self, nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        self.to_nvs_key(nvs, CONFIG_GOOD_NAME)
+    }
+
+    pub fn remove_good_nvs(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        nvs.remove(CONFIG_GOOD_NAME)?;
+        Ok(())
+    }
+
+    fn from_nvs_key(nvs: &mut nvs::EspNvs<nvs::NvsDefault>, key: &str) -> Option<Self> {
+        let size = match nvs.blob_len(key) {
+            Err(e) => {
+                error!("Nvs read error {e:?}");
+                return None;
+            }
+            Ok(Some(size)) => size,
+            _ => {
+                error!("Nvs key {key} not found");
+                return None;
+            }
+        };
+        if size > CONFIG_MAX_SIZE {
+            error!("Nvs key {key} is {size} bytes, more than {CONFIG_MAX_SIZE}");
+            return None;
+        }
+
+        let mut nvsbuf = vec![0u8; size];
+        info!("Reading {key} of {size} bytes from nvs...");
+        let b = match nvs.get_blob(key, &mut nvsbuf) {
             Err(e) => {
                 error!("Nvs read error {e:?}");
                 return None;
             }
             Ok(Some(b)) => b,
             _ => {
-                error!("Nvs key not found");
+                error!("Nvs key {key} not found");
                 return None;
             }
         };
         info!("Got {sz} bytes from nvs. Parsing config...", sz = b.len());
 
-        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
-        let digest = crc.digest();
-        match postcard::from_bytes_crc32::<MyConfig>(b, digest) {
+        match MyConfig::from_blob(b) {
             Ok(c) => {
                 info!("Successfully parsed config from nvs.");
                 Some(c)
             }
             Err(e) => {
-                error!("Cannot parse config from nvs: {e:?}");
+                error!("Cannot parse config from nvs: {e}");
                 None
             }
         }
     }
 
-    pub fn to_nvs(&self, nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
-        let mut nvsbuf = [0u8; NVS_BUF_SIZE];
-        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
-        let digest = crc.digest();
-        let nvsdata = match postcard::to_slice_crc32(self, &mut nvsbuf, digest) {
-            Ok(d) => d,
-            Err(e) => {
-                let estr = format!("Cannot encode config to buffer {e:?}");
-                bail!("{estr}");
-            }
-        };
+    fn to_nvs_key(&self, nvs: &mut nvs::EspNvs<nvs::NvsDefault>, key: &str) -> anyhow::Result<()> {
+        let nvsdata = self.to_blob()?;
         info!(
-            "Encoded config to {sz} bytes. Saving to nvs...",
+            "Encoded config to {sz} bytes. Saving {key} to nvs...",
             sz = nvsdata.len()
         );
 
-        match nvs.set_blob(CONFIG_NAME, nvsdata) {
+        match nvs.set_blob(key, &nvsdata) {
             Ok(_) => {
                 info!("Config saved.");
                 Ok(())
             }
+            Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_ENOUGH_SPACE as i32 => {
+                error!("Cannot save to nvs: {e:?}");
+                Err(ConfigStoreError::NvsFull.into())
+            }
             Err(e) => {
                 let estr = format!("Cannot save to nvs: {e:?}");
                 bail!("{estr}");
@@ -115,4 +541,137 @@ impl MyConfig {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_ip_needs_traffic() {
+        let config = MyConfig {
+            v4dhcp: false,
+            ..Default::default()
+        };
+        let waited = CONFIG_CONFIRM_TIMEOUT - Duration::from_secs(1);
+        assert_eq!(config.confirm_check(false, waited), ConfigCheck::Wait);
+        assert_eq!(
+            config.confirm_check(false, CONFIG_CONFIRM_TIMEOUT),
+            ConfigCheck::Fail
+        );
+        assert_eq!(config.confirm_check(true, waited), ConfigCheck::Confirm);
+
+        let config = MyConfig {
+            v4dhcp: true,
+            ..config
+        };
+        assert_eq!(
+            config.confirm_check(false, Duration::ZERO),
+            ConfigCheck::Confirm
+        );
+    }
+
+    #[test]
+    fn unmask_by_ssid() {
+        let current = MyConfig {
+            wifi_networks: vec![test_network("office", "hunter22", 0)],
+            ..Default::default()
+        };
+        let mut config = current.masked();
+        config.wifi_networks.insert(
+            0,
+            WifiNetwork {
+                ssid: "lab".into(),
+                pass: SECRET_MASK.into(),
+                ..Default::default()
+            },
+        );
+        config.unmask(&current);
+        assert_eq!(config.wifi_networks[0].pass, SECRET_MASK);
+        assert_eq!(config.wifi_networks[1].pass, "hunter22");
+    }
+
+    #[test]
+    fn blob_size_limit() {
+        // larger than the old fixed 256 byte buffer
+        let config = MyConfig {
+            wifi_networks: vec![WifiNetwork {
+                ssid: "office".into(),
+                wpa2ent: true,
+                username: "u".repeat(200),
+                ..Default::default()
+            }],
+            fw_manifest_url: format!("https://{}/manifest.json", "f".repeat(200)),
+            ..Default::default()
+        };
+        let blob = config.to_blob().unwrap();
+        assert!(blob.len() > 256);
+        assert_eq!(MyConfig::from_blob(&blob).unwrap(), config);
+
+        let config = MyConfig {
+            fw_manifest_url: "f".repeat(CONFIG_MAX_SIZE),
+            ..Default::default()
+        };
+        let err = config.to_blob().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigStoreError>(),
+            Some(ConfigStoreError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn export_masks_secrets() {
+        let config = MyConfig {
+            wifi_networks: vec![test_network("office", "hunter22", 0)],
+            admin_pass: "salt$digest".into(),
+            ..Default::default()
+        };
+
+        let export = config.export(false).unwrap();
+        assert_eq!(export.schema_version, CONFIG_SCHEMA_VERSION);
+        assert_eq!(export.config["wifi_networks"][0]["pass"], SECRET_MASK);
+        assert_eq!(export.config["admin_pass"], SECRET_MASK);
+
+        let export = config.export(true).unwrap();
+        assert_eq!(export.config["wifi_networks"][0]["pass"], "hunter22");
+        assert_eq!(export.config["admin_pass"], SECRET_MASK);
+    }
+
+    #[test]
+    fn import_roundtrip() {
+        let config = MyConfig {
+            wifi_networks: vec![test_network("office", "hunter22", 0)],
+            bps: 115200,
+            ..Default::default()
+        };
+        let export = config.export(true).unwrap();
+        let imported = MyConfig::from_export(export).unwrap();
+        assert_eq!(
+            imported.wifi_networks,
+            [test_network("office", "hunter22", 0)]
+        );
+        assert_eq!(imported.bps, 115200);
+    }
+
+    #[test]
+    fn import_older_and_newer() {
+        // a backup from before the serial framing setting existed
+        let export = ConfigExport {
+            fw_version: "1.3.2".into(),
+            schema_version: 0,
+            hw_feature: "esp32-c3".into(),
+            config: serde_json::json!({"wifi_ssid": "office", "bps": 57600}),
+        };
+        let imported = MyConfig::from_export(export).unwrap();
+        assert_eq!(imported.wifi_networks[0].ssid, "office");
+        assert_eq!(imported.serial_framing, DEFAULT_SERIAL_FRAMING);
+
+        let export = ConfigExport {
+            fw_version: "9.9.9".into(),
+            schema_version: CONFIG_SCHEMA_VERSION + 1,
+            hw_feature: "esp32-c3".into(),
+            config: serde_json::json!({"wifi_ssid": "office"}),
+        };
+        assert!(MyConfig::from_export(export).is_err());
+    }
+}
+
 // EOF
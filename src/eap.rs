@@ -0,0 +1,112 @@
+// eap.rs
+
+use std::ffi::{CStr, CString};
+
+use esp_idf_svc::nvs;
+
+use crate::*;
+
+const EAP_CA_NAME: &str = "eap_ca";
+const EAP_CERT_NAME: &str = "eap_cert";
+const EAP_KEY_NAME: &str = "eap_key";
+
+#[derive(Debug, Deserialize)]
+pub struct UploadEapCerts {
+    #[serde(default)]
+    pub ca: String,
+    #[serde(default)]
+    pub cert: String,
+    #[serde(default)]
+    pub key: String,
+}
+
+/// PEM encoded CA certificate and EAP-TLS client certificate and key.
+#[derive(Default)]
+pub struct EapCerts {
+    pub ca: Option<CString>,
+    pub cert: Option<CString>,
+    pub key: Option<CString>,
+}
+
+impl EapCerts {
+    /// Check that the uploaded certificates parse, and wrap them. Empty fields are left out.
+    pub fn new(upload: &UploadEapCerts) -> anyhow::Result<Self> {
+        let pem = |s: &str| match s.trim() {
+            "" => Ok(None),
+            s => CString::new(s).map(Some),
+        };
+        let certs = Self {
+            ca: pem(&upload.ca)?,
+            cert: pem(&upload.cert)?,
+            key: pem(&upload.key)?,
+        };
+
+        if let Some(ca) = &certs.ca {
+            check_cert_pem(ca)?;
+        }
+        match (&certs.cert, &certs.key) {
+            (Some(cert), Some(key)) => {
+                check_cert_pem(cert)?;
+                check_key_pem(key)?;
+            }
+            (None, None) => {}
+            _ => bail!("Client certificate and key must be given together"),
+        }
+        Ok(certs)
+    }
+
+    pub fn from_nvs(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<Self> {
+        let mut buf = vec![0u8; CERT_PEM_MAX];
+        let mut get = |key: &str| -> anyhow::Result<Option<CString>> {
+            match nvs.get_blob(key, &mut buf)? {
+                Some(pem) => Ok(Some(CString::new(pem)?)),
+                None => Ok(None),
+            }
+        };
+        Ok(Self {
+            ca: get(EAP_CA_NAME)?,
+            cert: get(EAP_CERT_NAME)?,
+            key: get(EAP_KEY_NAME)?,
+        })
+    }
+
+    fn entries(&self) -> [(&'static str, &Option<CString>); 3] {
+        [
+            (EAP_CA_NAME, &self.ca),
+            (EAP_CERT_NAME, &self.cert),
+            (EAP_KEY_NAME, &self.key),
+        ]
+    }
+
+    pub fn fits_nvs(&self) -> anyhow::Result<bool> {
+        let pems: Vec<&CStr> = self
+            .entries()
+            .iter()
+            .filter_map(|(_, pem)| pem.as_deref())
+            .collect();
+        pems_fit_nvs(&pems)
+    }
+
+    /// Replace the stored certificates, removing all of them if a write fails.
+    pub fn to_nvs(&self, nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        let res = self.entries().iter().try_for_each(|(key, pem)| {
+            match pem {
+                Some(pem) => nvs.set_blob(key, pem.as_bytes())?,
+                None => {
+                    nvs.remove(key)?;
+                }
+            }
+            Ok::<_, esp_idf_sys::EspError>(())
+        });
+        if let Err(e) = res {
+            for (key, _) in self.entries() {
+                nvs.remove(key).ok();
+            }
+            bail!("Cannot save EAP certificates, removed them: {e:?}");
+        }
+        info!("EAP certificates saved.");
+        Ok(())
+    }
+}
+
+// EOF
@@ -2,33 +2,188 @@
 
 document.addEventListener("DOMContentLoaded", function () {
     document.querySelector("form[name='esp32cfg']")
-        .addEventListener("submit", handleCfgSubmit);
+        ?.addEventListener("submit", handleCfgSubmit);
+    document.querySelector("form[name='esp32passwd']")
+        ?.addEventListener("submit", handlePasswdSubmit);
+    document.querySelector("form[name='esp32import']")
+        ?.addEventListener("submit", handleImportSubmit);
+    document.querySelector("button[name='wifi_scan']")
+        ?.addEventListener("click", handleWifiScan);
 });
 
+// offer the networks in range as choices for the SSID inputs
+const handleWifiScan = async (event) => {
+    const button = event.currentTarget;
+    const datalist = document.getElementById("wifi_scan");
+    button.disabled = true;
+    try {
+        const response = await fetch("/wifi/scan", {headers: {"Accept": "application/json"}});
+        if (!response.ok) {
+            throw new Error(await response.text());
+        }
+        const aps = await response.json();
+        datalist.replaceChildren(...aps.filter((ap) => ap.ssid).map((ap) => {
+            const option = document.createElement("option");
+            option.value = ap.ssid;
+            option.label = `${ap.rssi} dBm, channel ${ap.channel}, ${ap.auth}, ${ap.bssid}`;
+            return option;
+        }));
+        button.textContent = `Scan for networks (${datalist.children.length} found)`;
+    } catch (error) {
+        console.error(error);
+    } finally {
+        button.disabled = false;
+    }
+};
+
+const handleImportSubmit = async (event) => {
+    event.preventDefault();
+    const form = event.currentTarget;
+    const file = form.querySelector("input[type='file']").files[0];
+    if (!file) {
+        return;
+    }
+
+    try {
+        const response = await fetch(form.action, {
+            method: "POST",
+            headers: {"Accept": "application/json", "Content-Type": "application/json"},
+            body: await file.text()
+        });
+        if (!response.ok) {
+            throw new Error(await response.text());
+        }
+        const responseData = await response.json();
+        // show the imported settings, after the restart if one is needed
+        setTimeout(() => window.location.reload(), responseData.restart ? 10000 : 0);
+    } catch (error) {
+        console.error(error);
+    }
+};
+
+const handlePasswdSubmit = async (event) => {
+    event.preventDefault();
+    const form = event.currentTarget;
+
+    try {
+        const formObj = Object.fromEntries(new FormData(form).entries());
+        const response = await fetch(form.action, {
+            method: "POST",
+            headers: {"Content-Type": "application/json"},
+            body: JSON.stringify(formObj)
+        });
+        if (!response.ok) {
+            throw new Error(await response.text());
+        }
+        // the device restarts to take the new password into use
+        setTimeout(() => window.location.reload(), 5000);
+    } catch (error) {
+        console.error(error);
+    }
+};
+
 const handleCfgSubmit = async (event) => {
     event.preventDefault();
     const form = event.currentTarget;
     const url = form.action;
 
+    markFieldErrors(form, []);
     try {
         const formData = new FormData(form);
         const responseData = await postCfgDataAsJson({url, formData});
         console.log({responseData});
+        if (responseData.restart) {
+            // network settings changed, give the device time to come back up
+            setTimeout(() => window.location.reload(), 10000);
+        }
     } catch (error) {
+        if (error.fieldErrors) {
+            markFieldErrors(form, error.fieldErrors);
+        }
         console.error(error);
     }
 };
 
+// highlight inputs rejected by the config validation, clearing earlier marks
+const markFieldErrors = (form, fieldErrors) => {
+    form.querySelectorAll("input.invalid").forEach((input) => {
+        input.classList.remove("invalid");
+        input.removeAttribute("title");
+    });
+    for (const {field, message} of fieldErrors) {
+        const input = form.querySelector(`input[name='${field}']`);
+        if (input) {
+            input.classList.add("invalid");
+            input.title = message;
+        }
+    }
+    form.querySelector("input.invalid")?.focus();
+};
+
+// gather the wifi_networks.N.field inputs into a list, leaving out rows without an SSID;
+// returns the list and the form row of each network
+const collectNetworks = (formObj) => {
+    const rows = new Map();
+    for (const [key, value] of Object.entries(formObj)) {
+        const match = key.match(/^wifi_networks\.(\d+)\.(\w+)$/);
+        if (match) {
+            const row = parseInt(match[1], 10);
+            rows.set(row, {...rows.get(row), [match[2]]: value});
+            delete formObj[key];
+        }
+    }
+    const networks = [];
+    const formRows = [];
+    for (const [row, network] of [...rows].sort(([a], [b]) => a - b)) {
+        if (!network.ssid) {
+            continue;
+        }
+        networks.push({
+            ssid: network.ssid,
+            pass: network.pass ?? "",
+            wpa2ent: (network.wpa2ent === "on"),
+            eap_method: network.eap_method ?? "peap",
+            auth: network.auth ?? "auto",
+            pmf: network.pmf ?? "optional",
+            bssid: network.bssid ?? "",
+            channel: parseInt(network.channel, 10) || 0,
+            username: network.username ?? "",
+            priority: parseInt(network.priority, 10) || 0
+        });
+        formRows.push(row);
+    }
+    return {networks, formRows};
+};
+
 const postCfgDataAsJson = async ({url, formData}) => {
     const formObj = Object.fromEntries(formData.entries());
+    const {networks, formRows} = collectNetworks(formObj);
+    formObj.wifi_networks = networks;
     // convert integers
+    formObj.wifi_roam_rssi = parseInt(formObj.wifi_roam_rssi, 10);
+    formObj.wifi_roam_period = parseInt(formObj.wifi_roam_period, 10);
     formObj.v4mask = parseInt(formObj.v4mask, 10);
+    formObj.api_port = parseInt(formObj.api_port, 10);
     formObj.bps = parseInt(formObj.bps, 10);
     formObj.serial_tcp_port = parseInt(formObj.serial_tcp_port, 10);
+    formObj.serial_pkt_size = parseInt(formObj.serial_pkt_size, 10);
+    formObj.serial_pkt_timeout = parseInt(formObj.serial_pkt_timeout, 10);
+    formObj.fw_check_interval = parseInt(formObj.fw_check_interval, 10);
+    formObj.https_port = parseInt(formObj.https_port, 10);
+    formObj.watchdog_interval = parseInt(formObj.watchdog_interval, 10);
+    formObj.watchdog_failures = parseInt(formObj.watchdog_failures, 10);
+    // convert lists
+    formObj.watchdog_targets = formObj.watchdog_targets.split(",")
+        .map((target) => target.trim())
+        .filter((target) => target);
     // convert booleans
-    formObj.wifi_wpa2ent = (formObj.wifi_wpa2ent === "on");
+    formObj.wifi_roam_enabled = (formObj.wifi_roam_enabled === "on");
     formObj.v4dhcp = (formObj.v4dhcp === "on");
+    formObj.web_ui_enabled = (formObj.web_ui_enabled === "on");
     formObj.serial_write_enabled = (formObj.serial_write_enabled === "on");
+    formObj.https_enabled = (formObj.https_enabled === "on");
+    formObj.http_redirect = (formObj.http_redirect === "on");
+    formObj.http_disabled = (formObj.http_disabled === "on");
     // serialize to JSON
     const formDataJsonString = JSON.stringify(formObj);
 
@@ -41,6 +196,17 @@ const postCfgDataAsJson = async ({url, formData}) => {
     };
     const response = await fetch(url, fetchOptions);
 
+    if (response.status === 400 &&
+        response.headers.get("Content-Type")?.startsWith("application/json")) {
+        // point errors of a network at the form row it came from
+        const fieldErrors = (await response.json()).map(({field, message}) => ({
+            field: field.replace(/^wifi_networks\.(\d+)/, (m, i) => `wifi_networks.${formRows[i]}`),
+            message
+        }));
+        const error = new Error(fieldErrors.map((e) => `${e.field}: ${e.message}`).join("\n"));
+        error.fieldErrors = fieldErrors;
+        throw error;
+    }
     if (!response.ok) {
         const errorMessage = await response.text();
         throw new Error(errorMessage);
@@ -0,0 +1,168 @@
+// fwupdate.rs
+
+use embedded_svc::http::client::Client as HttpClient;
+use esp_idf_svc::{
+    http::client::{Configuration as HttpConfiguration, EspHttpConnection},
+    ota::EspOta,
+};
+use sha2::{Digest, Sha256};
+
+use crate::*;
+
+const FW_CHECK_INITIAL_DELAY: u64 = 60;
+const MANIFEST_MAX_SIZE: usize = 8192;
+
+#[derive(Debug, Deserialize)]
+pub struct FwManifestEntry {
+    pub version: String,
+    pub target: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+pub async fn run_fw_check(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    let config = state.config.read().await.clone();
+    if config.fw_manifest_url.is_empty() || config.fw_check_interval == 0 || state.safe_mode {
+        info!("Automatic firmware update checks disabled.");
+        std::future::pending::<()>().await;
+    }
+
+    loop {
+        if *state.wifi_up.read().await {
+            break;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    sleep(Duration::from_secs(FW_CHECK_INITIAL_DELAY)).await;
+    loop {
+        let url = config.fw_manifest_url.clone();
+        match task::spawn_blocking(move || check_fw_update(&url)).await {
+            Ok(Ok(true)) => {
+                info!("Update done. Restarting...");
+                requested_restart(&state).await;
+            }
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => error!("Firmware update check failed: {e:?}"),
+            Err(e) => error!("Firmware update check task failed: {e:?}"),
+        }
+        sleep(Duration::from_secs(config.fw_check_interval as u64 * 60)).await;
+    }
+}
+
+/// Install the newest matching firmware from the manifest, returning true if one was installed.
+fn check_fw_update(manifest_url: &str) -> anyhow::Result<bool> {
+    info!("Checking firmware manifest {manifest_url}");
+    let manifest = fetch_manifest(manifest_url)?;
+
+    let current = semver::Version::parse(FW_VERSION)?;
+    let mut newest: Option<(semver::Version, &FwManifestEntry)> = None;
+    for entry in manifest.iter().filter(|e| e.target == HW_FEATURE) {
+        let version = match semver::Version::parse(&entry.version) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Bad version {:?} in manifest: {e}", entry.version);
+                continue;
+            }
+        };
+        if version > current && newest.as_ref().is_none_or(|(v, _)| version > *v) {
+            newest = Some((version, entry));
+        }
+    }
+
+    let Some((version, entry)) = newest else {
+        info!("No newer firmware for {HW_FEATURE} (running {FW_VERSION}).");
+        return Ok(false);
+    };
+
+    info!(
+        "Found firmware {version} for {HW_FEATURE}, installing from {}",
+        entry.url
+    );
+    install_fw(&entry.url, Some(&entry.sha256))?;
+    Ok(true)
+}
+
+/// HTTP client that checks https servers against the ESP-IDF certificate bundle.
+fn http_client() -> anyhow::Result<HttpClient<EspHttpConnection>> {
+    let config = HttpConfiguration {
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    Ok(HttpClient::wrap(EspHttpConnection::new(&config)?))
+}
+
+fn fetch_manifest(url: &str) -> anyhow::Result<Vec<FwManifestEntry>> {
+    let mut client = http_client()?;
+    let mut resp = client.get(url)?.submit()?;
+    if resp.status() != 200 {
+        bail!("Manifest fetch returned HTTP status {}", resp.status());
+    }
+
+    let mut body = Vec::new();
+    let mut buffer = [0_u8; 512];
+    loop {
+        let n = resp.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        if body.len() + n > MANIFEST_MAX_SIZE {
+            bail!("Manifest is larger than {MANIFEST_MAX_SIZE} bytes");
+        }
+        body.extend_from_slice(&buffer[..n]);
+    }
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Download a firmware image into the next OTA slot, checking `sha256` if given.
+pub fn install_fw(url: &str, sha256: Option<&str>) -> anyhow::Result<()> {
+    let mut ota = EspOta::new()?;
+    let mut client = http_client()?;
+    let mut resp = client.get(url)?.submit()?;
+    if resp.status() != 200 {
+        bail!("Firmware fetch returned HTTP status {}", resp.status());
+    }
+
+    let mut update = ota.initiate_update()?;
+    let mut hasher = Sha256::new();
+    // on the heap, blocking threads have a small stack
+    let mut buffer = vec![0_u8; 8192];
+    let mut total = 0;
+    loop {
+        let n = match resp.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) => {
+                update.abort()?;
+                bail!("Firmware download failed after {total} bytes: {e:?}");
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        if let Err(e) = update.write(&buffer[..n]) {
+            update.abort()?;
+            bail!("Firmware write failed after {total} bytes: {e:?}");
+        }
+        hasher.update(&buffer[..n]);
+        total += n;
+    }
+    info!("Downloaded {total} bytes of firmware.");
+
+    if let Some(expected) = sha256 {
+        let digest = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        if !digest.eq_ignore_ascii_case(expected.trim()) {
+            update.abort()?;
+            bail!("Firmware sha256 mismatch: expected {expected}, got {digest}");
+        }
+    }
+
+    update.complete()?;
+    Ok(())
+}
+
+// EOF
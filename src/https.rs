@@ -0,0 +1,423 @@
+// https.rs
+
+use std::{
+    ffi::{CStr, CString, c_void},
+    io::{self, Read, Write},
+    mem::ManuallyDrop,
+    net::TcpStream as StdTcpStream,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd},
+    time::Instant,
+};
+
+use esp_idf_svc::{
+    nvs,
+    sys::{self as sys, EspError},
+    tls::{EspTls, ServerConfig, Socket, X509},
+};
+use tokio::{net::TcpListener, sync::Semaphore};
+
+use crate::*;
+
+const TLS_CERT_NAME: &str = "tls_cert";
+const TLS_KEY_NAME: &str = "tls_key";
+// TLS and EAP certificates and keys are read back from nvs into buffers of this size
+pub const CERT_PEM_MAX: usize = 4096;
+// nvs stores data in 32 byte entries
+const NVS_ENTRY_SIZE: usize = 32;
+const TLS_MAX_CLIENTS: usize = 2;
+const TLS_THREAD_STACK: usize = 12 * 1024;
+const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const TLS_BUFSZ: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct UploadTlsCert {
+    pub cert: String,
+    pub key: String,
+}
+
+/// PEM encoded server certificate and private key.
+pub struct TlsCert {
+    pub cert: CString,
+    pub key: CString,
+}
+
+unsafe extern "C" fn tls_rng(_ctx: *mut c_void, buf: *mut u8, len: usize) -> i32 {
+    unsafe { sys::esp_fill_random(buf as *mut c_void, len) };
+    0
+}
+
+/// True if the PEMs fit in the free nvs space next to the ones they replace.
+pub fn pems_fit_nvs(pems: &[&CStr]) -> anyhow::Result<bool> {
+    let mut stats = sys::nvs_stats_t::default();
+    sys::esp!(unsafe { sys::nvs_get_stats(core::ptr::null(), &mut stats) })?;
+
+    // a blob of up to two chunks, each with a header, plus its index entry
+    let needed: usize = pems
+        .iter()
+        .map(|pem| pem.to_bytes().len().div_ceil(NVS_ENTRY_SIZE) + 3)
+        .sum();
+    Ok(needed <= stats.available_entries)
+}
+
+/// Check that a PEM certificate, or a chain of them, parses.
+pub fn check_cert_pem(cert: &CStr) -> anyhow::Result<()> {
+    unsafe {
+        let mut crt = sys::mbedtls_x509_crt::default();
+        sys::mbedtls_x509_crt_init(&mut crt);
+        let ret = sys::mbedtls_x509_crt_parse(
+            &mut crt,
+            cert.as_ptr().cast(),
+            cert.to_bytes_with_nul().len(),
+        );
+        sys::mbedtls_x509_crt_free(&mut crt);
+        if ret != 0 {
+            bail!("Cannot parse certificate: mbedtls error -0x{:04x}", -ret);
+        }
+    }
+    Ok(())
+}
+
+/// Check that an unencrypted PEM private key parses.
+pub fn check_key_pem(key: &CStr) -> anyhow::Result<()> {
+    unsafe {
+        let mut pk = sys::mbedtls_pk_context::default();
+        sys::mbedtls_pk_init(&mut pk);
+        let ret = sys::mbedtls_pk_parse_key(
+            &mut pk,
+            key.as_ptr().cast(),
+            key.to_bytes_with_nul().len(),
+            core::ptr::null(),
+            0,
+            Some(tls_rng),
+            core::ptr::null_mut(),
+        );
+        sys::mbedtls_pk_free(&mut pk);
+        if ret != 0 {
+            bail!("Cannot parse private key: mbedtls error -0x{:04x}", -ret);
+        }
+    }
+    Ok(())
+}
+
+impl TlsCert {
+    /// Check that the certificate and key parse, and wrap them.
+    pub fn new(cert: &str, key: &str) -> anyhow::Result<Self> {
+        let cert = CString::new(cert.trim())?;
+        let key = CString::new(key.trim())?;
+        check_cert_pem(&cert)?;
+        check_key_pem(&key)?;
+        Ok(Self { cert, key })
+    }
+
+    /// Generate a self-signed EC P-256 certificate for `cn`.
+    pub fn generate(cn: &str) -> anyhow::Result<Self> {
+        let subject = CString::new(format!("CN={cn},O=esp32serial"))?;
+        let mut cert_pem = vec![0u8; CERT_PEM_MAX];
+        let mut key_pem = vec![0u8; CERT_PEM_MAX];
+        let mut serial = [0u8; 16];
+
+        unsafe {
+            sys::esp_fill_random(serial.as_mut_ptr() as *mut c_void, serial.len());
+            // keep the serial number positive
+            serial[0] &= 0x7f;
+
+            let mut pk = sys::mbedtls_pk_context::default();
+            let mut crt = sys::mbedtls_x509write_cert::default();
+            sys::mbedtls_pk_init(&mut pk);
+            sys::mbedtls_x509write_crt_init(&mut crt);
+
+            let res = (|| {
+                let check = |what: &str, ret: i32| match ret {
+                    0 => Ok(()),
+                    e => Err(anyhow::anyhow!(
+                        "{what} failed: mbedtls error -0x{:04x}",
+                        -e
+                    )),
+                };
+
+                check(
+                    "pk_setup",
+                    sys::mbedtls_pk_setup(
+                        &mut pk,
+                        sys::mbedtls_pk_info_from_type(sys::mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+                    ),
+                )?;
+                check(
+                    "ecp_gen_key",
+                    sys::mbedtls_ecp_gen_key(
+                        sys::mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                        pk.private_pk_ctx as *mut sys::mbedtls_ecp_keypair,
+                        Some(tls_rng),
+                        core::ptr::null_mut(),
+                    ),
+                )?;
+
+                sys::mbedtls_x509write_crt_set_version(
+                    &mut crt,
+                    sys::MBEDTLS_X509_CRT_VERSION_3 as i32,
+                );
+                sys::mbedtls_x509write_crt_set_md_alg(
+                    &mut crt,
+                    sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                );
+                sys::mbedtls_x509write_crt_set_subject_key(&mut crt, &mut pk);
+                sys::mbedtls_x509write_crt_set_issuer_key(&mut crt, &mut pk);
+                check(
+                    "set_subject_name",
+                    sys::mbedtls_x509write_crt_set_subject_name(&mut crt, subject.as_ptr()),
+                )?;
+                check(
+                    "set_issuer_name",
+                    sys::mbedtls_x509write_crt_set_issuer_name(&mut crt, subject.as_ptr()),
+                )?;
+                check(
+                    "set_serial",
+                    sys::mbedtls_x509write_crt_set_serial_raw(
+                        &mut crt,
+                        serial.as_mut_ptr(),
+                        serial.len(),
+                    ),
+                )?;
+                // the clock is usually not set yet, so use a wide fixed validity period
+                check(
+                    "set_validity",
+                    sys::mbedtls_x509write_crt_set_validity(
+                        &mut crt,
+                        c"20240101000000".as_ptr(),
+                        c"20491231235959".as_ptr(),
+                    ),
+                )?;
+                check(
+                    "set_basic_constraints",
+                    sys::mbedtls_x509write_crt_set_basic_constraints(&mut crt, 0, -1),
+                )?;
+
+                check(
+                    "x509write_crt_pem",
+                    sys::mbedtls_x509write_crt_pem(
+                        &mut crt,
+                        cert_pem.as_mut_ptr(),
+                        cert_pem.len(),
+                        Some(tls_rng),
+                        core::ptr::null_mut(),
+                    ),
+                )?;
+                check(
+                    "pk_write_key_pem",
+                    sys::mbedtls_pk_write_key_pem(&pk, key_pem.as_mut_ptr(), key_pem.len()),
+                )
+            })();
+
+            sys::mbedtls_x509write_crt_free(&mut crt);
+            sys::mbedtls_pk_free(&mut pk);
+            res?;
+        }
+
+        Ok(Self {
+            cert: CStr::from_bytes_until_nul(&cert_pem)?.into(),
+            key: CStr::from_bytes_until_nul(&key_pem)?.into(),
+        })
+    }
+
+    pub fn from_nvs(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<Option<Self>> {
+        let mut cert_buf = vec![0u8; CERT_PEM_MAX];
+        let mut key_buf = vec![0u8; CERT_PEM_MAX];
+        let (Some(cert), Some(key)) = (
+            nvs.get_blob(TLS_CERT_NAME, &mut cert_buf)?,
+            nvs.get_blob(TLS_KEY_NAME, &mut key_buf)?,
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            cert: CString::new(cert)?,
+            key: CString::new(key)?,
+        }))
+    }
+
+    pub fn fits_nvs(&self) -> anyhow::Result<bool> {
+        pems_fit_nvs(&[&self.cert, &self.key])
+    }
+
+    /// Store the pair, removing both if either write fails.
+    pub fn to_nvs(&self, nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        let res = nvs
+            .set_blob(TLS_CERT_NAME, self.cert.as_bytes())
+            .and_then(|_| nvs.set_blob(TLS_KEY_NAME, self.key.as_bytes()));
+        if let Err(e) = res {
+            nvs.remove(TLS_CERT_NAME).ok();
+            nvs.remove(TLS_KEY_NAME).ok();
+            bail!("Cannot save TLS certificate, removed it: {e:?}");
+        }
+        info!("TLS certificate saved.");
+        Ok(())
+    }
+
+    pub fn remove_nvs(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        nvs.remove(TLS_CERT_NAME)?;
+        nvs.remove(TLS_KEY_NAME)?;
+        info!("TLS certificate removed.");
+        Ok(())
+    }
+}
+
+// Lends a std socket to esp-tls, which closes it when the session is destroyed.
+struct TlsSocket(Option<StdTcpStream>);
+
+impl Socket for TlsSocket {
+    fn handle(&self) -> i32 {
+        self.0.as_ref().map(|s| s.as_raw_fd()).unwrap_or(-1)
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        if let Some(s) = self.0.take() {
+            let _ = s.into_raw_fd();
+        }
+        Ok(())
+    }
+}
+
+pub async fn run_https_server(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    let config = state.config.read().await.clone();
+    if !config.https_enabled || !config.web_ui_enabled || state.safe_mode {
+        info!("HTTPS server disabled.");
+        std::future::pending::<()>().await;
+    }
+
+    loop {
+        if *state.wifi_up.read().await {
+            break;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    let tls_cert = {
+        let mut nvs = state.nvs.write().await;
+        let cn = state.myid.read().await.clone();
+        match TlsCert::from_nvs(&mut nvs) {
+            Ok(Some(c)) => c,
+            Ok(None) => {
+                info!("Generating self-signed TLS certificate for {cn}...");
+                let c = TlsCert::generate(&cn)?;
+                if let Err(e) = c.to_nvs(&mut nvs) {
+                    error!("{e:?}");
+                }
+                c
+            }
+            Err(e) => {
+                // keep the stored one, it may be fixed by uploading it again
+                error!("Cannot read TLS certificate from nvs: {e:?}");
+                info!("Serving a temporary self-signed TLS certificate for {cn}.");
+                TlsCert::generate(&cn)?
+            }
+        }
+    };
+    let tls_cert = Arc::new(tls_cert);
+
+    let listen = format!("0.0.0.0:{}", config.https_port);
+    let listener = TcpListener::bind(&listen).await?;
+    info!("HTTPS server listening to {listen}");
+
+    // further connections wait in the listen backlog until a client thread is free
+    let clients = Arc::new(Semaphore::new(TLS_MAX_CLIENTS));
+    loop {
+        let permit = clients.clone().acquire_owned().await?;
+        let (stream, addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("HTTPS accept failed: {e}");
+                continue;
+            }
+        };
+
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let tls_cert = tls_cert.clone();
+        let res = std::thread::Builder::new()
+            .stack_size(TLS_THREAD_STACK)
+            .spawn(move || {
+                if let Err(e) = handle_tls_client(stream, &tls_cert) {
+                    error!("HTTPS client {addr} error: {e:?}");
+                }
+                drop(permit);
+            });
+        if let Err(e) = res {
+            error!("Cannot spawn HTTPS client thread: {e}");
+        }
+    }
+}
+
+fn tls_would_block(e: &EspError) -> bool {
+    e.code() == sys::ESP_TLS_ERR_SSL_WANT_READ || e.code() == sys::ESP_TLS_ERR_SSL_WANT_WRITE
+}
+
+fn handle_tls_client(stream: StdTcpStream, tls_cert: &TlsCert) -> anyhow::Result<()> {
+    let fd = stream.as_raw_fd();
+    let mut tls = EspTls::adopt(TlsSocket(Some(stream)))?;
+    tls.negotiate_server(&ServerConfig {
+        server_cert: Some(X509::pem(&tls_cert.cert)),
+        server_key: Some(X509::pem(&tls_cert.key)),
+        ..Default::default()
+    })?;
+
+    // The handshake is done, pump data both ways without blocking on either side.
+    // esp-tls still owns the socket, we only flip its mode here.
+    let sock = ManuallyDrop::new(unsafe { StdTcpStream::from_raw_fd(fd) });
+    sock.set_nonblocking(true)?;
+    let mut upstream = StdTcpStream::connect((net::Ipv4Addr::LOCALHOST, HTTPS_PROXY_PORT))?;
+    upstream.set_nonblocking(true)?;
+
+    let mut buf = [0u8; TLS_BUFSZ];
+    let mut last_active = Instant::now();
+    loop {
+        let mut active = false;
+
+        match tls.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let mut data = &buf[..n];
+                while !data.is_empty() {
+                    match upstream.write(data) {
+                        Ok(w) => data = &data[w..],
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(1))
+                        }
+                        Err(e) => bail!(e),
+                    }
+                }
+                active = true;
+            }
+            Err(e) if tls_would_block(&e) => {}
+            Err(e) => bail!(e),
+        }
+
+        match upstream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let mut data = &buf[..n];
+                while !data.is_empty() {
+                    match tls.write(data) {
+                        Ok(w) => data = &data[w..],
+                        Err(e) if tls_would_block(&e) => {
+                            std::thread::sleep(Duration::from_millis(1))
+                        }
+                        Err(e) => bail!(e),
+                    }
+                }
+                active = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => bail!(e),
+        }
+
+        if active {
+            last_active = Instant::now();
+        } else if last_active.elapsed() > TLS_IDLE_TIMEOUT {
+            break;
+        } else {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    Ok(())
+}
+
+// EOF
@@ -1,5 +1,7 @@
 // lib.rs
 #![warn(clippy::large_futures)]
+// host builds only run the unit tests, most of the firmware is left out
+#![cfg_attr(not(target_os = "espidf"), allow(dead_code, unused_imports))]
 
 pub use anyhow::bail;
 use serde::{Deserialize, Serialize};
@@ -18,23 +20,83 @@ pub use tokio::{
 };
 pub use tracing::*;
 
+#[cfg(target_os = "espidf")]
 pub use apiserver::*;
+pub use auth::*;
+pub use captive::*;
 pub use config::*;
+#[cfg(target_os = "espidf")]
+pub use eap::*;
+#[cfg(target_os = "espidf")]
+pub use fwupdate::*;
+#[cfg(target_os = "espidf")]
+pub use https::*;
+pub use mdns::*;
+pub use migrate::*;
+#[cfg(target_os = "espidf")]
+pub use recovery::*;
+#[cfg(target_os = "espidf")]
 pub use serial::*;
+#[cfg(target_os = "espidf")]
 pub use state::*;
+#[cfg(target_os = "espidf")]
+pub use status::*;
+pub use validate::*;
+#[cfg(target_os = "espidf")]
+pub use watchdog::*;
 pub use wifi::*;
 
 pub const FW_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_BRANCH: Option<&str> = option_env!("GIT_BRANCH");
+pub const GIT_COMMIT: Option<&str> = option_env!("GIT_COMMIT");
+pub const SOURCE_TIMESTAMP: Option<&str> = option_env!("SOURCE_TIMESTAMP");
+pub const RUSTC_VERSION: Option<&str> = option_env!("RUSTC_VERSION");
+
+#[cfg(feature = "esp32-c3")]
+pub const HW_FEATURE: &str = "esp32-c3";
+#[cfg(all(feature = "esp-wroom-32", not(feature = "esp32-c3")))]
+pub const HW_FEATURE: &str = "esp-wroom-32";
 
 #[derive(Debug, Deserialize)]
 pub struct UpdateFirmware {
     url: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    secrets: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigSaved {
+    restart: bool,
+}
+
+#[cfg(target_os = "espidf")]
 mod apiserver;
+mod auth;
+mod captive;
 mod config;
+#[cfg(target_os = "espidf")]
+mod eap;
+#[cfg(target_os = "espidf")]
+mod fwupdate;
+#[cfg(target_os = "espidf")]
+mod https;
+mod mdns;
+mod migrate;
+#[cfg(target_os = "espidf")]
+mod recovery;
+#[cfg(target_os = "espidf")]
 mod serial;
+#[cfg(target_os = "espidf")]
 mod state;
+#[cfg(target_os = "espidf")]
+mod status;
+mod validate;
+#[cfg(target_os = "espidf")]
+mod watchdog;
 mod wifi;
 
 // EOF
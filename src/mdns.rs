@@ -0,0 +1,159 @@
+// mdns.rs
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::mdns::EspMdns;
+
+use crate::*;
+
+/// A DNS-SD service to advertise over TCP.
+#[derive(Debug, PartialEq)]
+struct MdnsService {
+    service_type: &'static str,
+    port: u16,
+    txt: Vec<(&'static str, String)>,
+}
+
+/// Advertise `<hostname>.local` and the web UI and serial bridge services.
+#[cfg(target_os = "espidf")]
+pub async fn run_mdns(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    loop {
+        if *state.wifi_up.read().await {
+            break;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    let hostname = state.hostname().await;
+    // mDNS is optional, an error here must not end main() and reboot
+    let mut mdns = match mdns_start(&hostname) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("mDNS disabled: {e:?}");
+            std::future::pending::<()>().await;
+            return Ok(());
+        }
+    };
+    info!("mDNS advertising {hostname}.local");
+
+    loop {
+        // created before reading the config, so that no change is missed
+        let refresh = state.mdns_refresh.notified();
+
+        let config = state.config.read().await.clone();
+        if let Err(e) = mdns.remove_services() {
+            error!("mDNS could not remove services: {e:?}");
+        }
+        for service in mdns_services(&config, state.safe_mode) {
+            info!(
+                "mDNS service {} on port {}",
+                service.service_type, service.port
+            );
+            let txt = service
+                .txt
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect::<Vec<_>>();
+            if let Err(e) = mdns.add_service(None, service.service_type, "_tcp", service.port, &txt)
+            {
+                error!("mDNS could not add {}: {e:?}", service.service_type);
+            }
+        }
+
+        refresh.await;
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn mdns_start(hostname: &str) -> anyhow::Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(hostname)?;
+    Ok(mdns)
+}
+
+/// The services that are up with this config.
+fn mdns_services(config: &MyConfig, safe_mode: bool) -> Vec<MdnsService> {
+    let mut services = Vec::new();
+
+    // safe mode always serves plain HTTP
+    let https = config.https_enabled && config.web_ui_enabled && !safe_mode;
+    let http = safe_mode || (config.web_ui_enabled && !(https && config.http_disabled));
+    if http {
+        services.push(MdnsService {
+            service_type: "_http",
+            port: config.api_port,
+            txt: vec![("path", "/".into())],
+        });
+    }
+    if https {
+        services.push(MdnsService {
+            service_type: "_https",
+            port: config.https_port,
+            txt: vec![("path", "/".into())],
+        });
+    }
+
+    if !safe_mode {
+        let write = match config.serial_write_enabled {
+            true => "on",
+            false => "off",
+        };
+        services.push(MdnsService {
+            service_type: "_esp32serial",
+            port: config.serial_tcp_port,
+            txt: vec![
+                ("baud", config.bps.to_string()),
+                ("framing", config.serial_framing.clone()),
+                ("write", write.into()),
+            ],
+        });
+    }
+    services
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(services: &[MdnsService]) -> Vec<&str> {
+        services.iter().map(|s| s.service_type).collect()
+    }
+
+    #[test]
+    fn services_follow_config() {
+        let config = MyConfig {
+            bps: 57600,
+            serial_write_enabled: false,
+            ..Default::default()
+        };
+        let services = mdns_services(&config, false);
+        assert_eq!(types(&services), ["_http", "_esp32serial"]);
+        assert_eq!(
+            services[1].txt,
+            [
+                ("baud", "57600".to_string()),
+                ("framing", "8N1".to_string()),
+                ("write", "off".to_string()),
+            ]
+        );
+
+        let config = MyConfig {
+            https_enabled: true,
+            http_disabled: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            types(&mdns_services(&config, false)),
+            ["_https", "_esp32serial"]
+        );
+        assert_eq!(types(&mdns_services(&config, true)), ["_http"]);
+
+        let config = MyConfig {
+            web_ui_enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(types(&mdns_services(&config, false)), ["_esp32serial"]);
+    }
+}
+
+// EOF
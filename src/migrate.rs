@@ -0,0 +1,198 @@
+// migrate.rs
+//
+// Config blobs in NVS start with a two byte header, CONFIG_MAGIC and the schema version,
+// followed by the postcard encoding with CRC. Blobs written before the header existed
+// are schema version 0; they start with the SSID length, which never has the top bit set.
+//
+// When MyConfig changes, bump CONFIG_SCHEMA_VERSION and keep the previous layout here
+// as ConfigV<n>. Old layouts are upgraded through JSON, like backups: renamed fields are
+// handled in `upgrade_export()` and new fields get their defaults in `MyConfig::merged()`.
+
+use crc::{CRC_32_ISCSI, Crc};
+
+use crate::*;
+
+pub const CONFIG_MAGIC: u8 = 0xC5;
+pub const CONFIG_SCHEMA_VERSION: u8 = 1;
+
+/// Layout of firmware 1.3.2 and earlier, stored without a header.
+#[derive(Debug, Serialize, Deserialize)]
+struct ConfigV0 {
+    wifi_ssid: String,
+    wifi_pass: String,
+    wifi_wpa2ent: bool,
+    wifi_username: String,
+
+    v4dhcp: bool,
+    v4addr: net::Ipv4Addr,
+    v4mask: u8,
+    v4gw: net::Ipv4Addr,
+    dns1: net::Ipv4Addr,
+    dns2: net::Ipv4Addr,
+
+    bps: u32,
+    serial_tcp_port: u16,
+    serial_write_enabled: bool,
+}
+
+fn decode<T: serde::de::DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    let crc = Crc::<u32>::new(&CRC_32_ISCSI);
+    postcard::from_bytes_crc32::<T>(payload, crc.digest()).map_err(|e| format!("{e:?}"))
+}
+
+/// Upgrade a decoded old layout to the current MyConfig.
+fn upgrade<T: Serialize>(version: u8, old: T) -> Result<MyConfig, String> {
+    let mut config =
+        serde_json::to_value(old).map_err(|e| format!("Cannot encode old config: {e}"))?;
+    upgrade_export(version, &mut config);
+    MyConfig::default().merged(config)
+}
+
+/// Split a stored blob into its schema version and postcard payload.
+pub fn split_header(blob: &[u8]) -> (u8, &[u8]) {
+    match blob {
+        [CONFIG_MAGIC, version, payload @ ..] => (*version, payload),
+        _ => (0, blob),
+    }
+}
+
+/// Decode a payload of the given schema version and upgrade it to the current MyConfig.
+pub fn upgrade_config(version: u8, payload: &[u8]) -> Result<MyConfig, String> {
+    if version < CONFIG_SCHEMA_VERSION {
+        info!("Migrating config from schema version {version}");
+    }
+    match version {
+        0 => upgrade(version, decode::<ConfigV0>(payload)?),
+        CONFIG_SCHEMA_VERSION => decode::<MyConfig>(payload),
+        v => Err(format!(
+            "Config schema version {v} is newer than supported {CONFIG_SCHEMA_VERSION}"
+        )),
+    }
+}
+
+/// Rewrite the config object of a JSON backup from an older schema to the current field names.
+pub fn upgrade_export(version: u8, config: &mut serde_json::Value) {
+    let Some(fields) = config.as_object_mut() else {
+        return;
+    };
+
+    if version < 1 {
+        let mut take = |name: &str| fields.remove(name).unwrap_or(serde_json::Value::Null);
+        let (ssid, pass, wpa2ent, username) = (
+            take("wifi_ssid"),
+            take("wifi_pass"),
+            take("wifi_wpa2ent"),
+            take("wifi_username"),
+        );
+        if ssid.as_str().is_some_and(|s| !s.is_empty()) {
+            let network = serde_json::json!({
+                "ssid": ssid,
+                "pass": pass.as_str().unwrap_or_default(),
+                "wpa2ent": wpa2ent.as_bool().unwrap_or_default(),
+                "username": username.as_str().unwrap_or_default(),
+                "priority": 0,
+            });
+            fields.insert("wifi_networks".into(), serde_json::json!([network]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // written by firmware 1.3.2: "office"/"hunter22", static 192.168.1.10/24,
+    // gateway 192.168.1.1, dns 1.1.1.1, 115200 bps on port 2323, read-only
+    const BLOB_V0: [u8; 46] = [
+        6, 111, 102, 102, 105, 99, 101, 8, 104, 117, 110, 116, 101, 114, 50, 50, 0, 0, 0, 192, 168,
+        1, 10, 24, 192, 168, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 128, 132, 7, 147, 18, 0, 212, 95, 86,
+        164,
+    ];
+
+    #[test]
+    fn decode_v0_blob() {
+        let config = MyConfig::from_blob(&BLOB_V0).unwrap();
+        assert_eq!(config.wifi_networks.len(), 1);
+        assert_eq!(config.wifi_networks[0].ssid, "office");
+        assert_eq!(config.wifi_networks[0].pass, "hunter22");
+        assert_eq!(config.wifi_networks[0].eap_method, EapMethod::Peap);
+        assert!(!config.v4dhcp);
+        assert_eq!(config.v4addr, net::Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(config.v4mask, 24);
+        assert_eq!(config.v4gw, net::Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(config.dns1, net::Ipv4Addr::new(1, 1, 1, 1));
+        assert_eq!(config.bps, 115200);
+        assert_eq!(config.serial_tcp_port, 2323);
+        assert!(!config.serial_write_enabled);
+
+        // fields added later come from the defaults
+        let default = MyConfig::default();
+        assert_eq!(config.api_port, default.api_port);
+        assert_eq!(config.serial_framing, default.serial_framing);
+        assert_eq!(config.admin_pass, "");
+        assert!(!config.https_enabled);
+        assert_eq!(config.watchdog_check, WatchdogCheck::Ping);
+        assert_eq!(config.watchdog_interval, default.watchdog_interval);
+        assert_eq!(config.hostname, "");
+    }
+
+    #[test]
+    fn upgrade_v0_export() {
+        let mut config = serde_json::json!({
+            "wifi_ssid": "office",
+            "wifi_pass": "hunter22",
+            "wifi_wpa2ent": false,
+            "wifi_username": "",
+            "bps": 57600,
+        });
+        upgrade_export(0, &mut config);
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "wifi_networks": [{
+                    "ssid": "office",
+                    "pass": "hunter22",
+                    "wpa2ent": false,
+                    "username": "",
+                    "priority": 0,
+                }],
+                "bps": 57600,
+            })
+        );
+
+        let mut config = serde_json::json!({"wifi_ssid": "", "bps": 57600});
+        upgrade_export(0, &mut config);
+        assert_eq!(config, serde_json::json!({"bps": 57600}));
+    }
+
+    #[test]
+    fn current_roundtrip() {
+        let config = MyConfig {
+            wifi_networks: vec![WifiNetwork {
+                ssid: "office".into(),
+                pass: "hunter22".into(),
+                ..Default::default()
+            }],
+            bps: 57600,
+            serial_framing: "7E1".into(),
+            https_enabled: true,
+            ..Default::default()
+        };
+        let blob = config.to_blob().unwrap();
+        assert_eq!(blob[..2], [CONFIG_MAGIC, CONFIG_SCHEMA_VERSION]);
+        assert_eq!(MyConfig::from_blob(&blob).unwrap(), config);
+    }
+
+    #[test]
+    fn reject_bad_blobs() {
+        let mut blob = MyConfig::default().to_blob().unwrap();
+        blob[1] = CONFIG_SCHEMA_VERSION + 1;
+        assert!(MyConfig::from_blob(&blob).is_err());
+
+        let mut blob = BLOB_V0;
+        blob[1] ^= 0xff;
+        assert!(MyConfig::from_blob(&blob).is_err());
+    }
+}
+
+// EOF
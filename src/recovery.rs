@@ -0,0 +1,170 @@
+// recovery.rs
+
+use esp_idf_hal::reset::ResetReason;
+use esp_idf_svc::nvs;
+use tokio::time::Instant;
+
+use crate::*;
+
+// boots so far with a config that has not brought up WiFi yet
+const CONFIG_TRIES_NAME: &str = "cfg_tries";
+// boots in a row that did not reach a stable uptime
+const BOOT_COUNT_NAME: &str = "boot_cnt";
+const BOOT_STABLE_UPTIME: Duration = Duration::from_secs(120);
+// set right before a restart asked for by the user
+const RESTART_REQ_NAME: &str = "restart_req";
+const CONFIG_CONFIRM_RETRY: Duration = Duration::from_secs(10);
+
+/// Count this boot, returning true if we should come up in safe mode.
+pub fn count_boot(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<bool> {
+    let requested = nvs.get_u8(RESTART_REQ_NAME)?.unwrap_or(0) != 0;
+    if requested {
+        nvs.set_u8(RESTART_REQ_NAME, 0)?;
+    }
+    let reason = ResetReason::get();
+    if requested || matches!(reason, ResetReason::PowerOn | ResetReason::ExternalPin) {
+        info!("Boot after {reason:?} reset (requested: {requested}), not counted.");
+        return Ok(false);
+    }
+
+    let boots = nvs.get_u8(BOOT_COUNT_NAME)?.unwrap_or(0).saturating_add(1);
+    nvs.set_u8(BOOT_COUNT_NAME, boots)?;
+    if boots > BOOT_FAIL_MAX {
+        error!("Boot {boots} without a stable uptime, entering safe mode.");
+        return Ok(true);
+    }
+    info!("Boot {boots}/{BOOT_FAIL_MAX} since the last stable uptime.");
+    Ok(false)
+}
+
+pub fn clear_boot_count(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+    nvs.set_u8(BOOT_COUNT_NAME, 0)?;
+    Ok(())
+}
+
+/// Restart on request of the user, without counting the next boot towards safe mode.
+pub async fn requested_restart(state: &MyState) -> ! {
+    if let Err(e) = state.nvs.write().await.set_u8(RESTART_REQ_NAME, 1) {
+        error!("Could not mark the restart as requested: {e:?}");
+    }
+    esp_idf_hal::reset::restart();
+}
+
+/// Clear the boot counter once we have been up long enough.
+pub async fn run_boot_watch(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    sleep(BOOT_STABLE_UPTIME).await;
+    info!("Uptime is stable, clearing boot counter.");
+    // returning would end main() and reboot, which counts as a failed boot again
+    if let Err(e) = clear_boot_count(&mut *state.nvs.write().await) {
+        error!("Could not clear boot counter: {e:?}");
+    }
+
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+/// Restore the known-good config once the config read at boot has failed too often.
+pub fn check_config_fallback(
+    nvs: &mut nvs::EspNvs<nvs::NvsDefault>,
+    config: MyConfig,
+) -> anyhow::Result<MyConfig> {
+    let Some(good) = MyConfig::good_from_nvs(nvs) else {
+        info!("No known-good config yet.");
+        return Ok(config);
+    };
+    if good == config {
+        nvs.set_u8(CONFIG_TRIES_NAME, 0)?;
+        return Ok(config);
+    }
+
+    let tries = nvs
+        .get_u8(CONFIG_TRIES_NAME)?
+        .unwrap_or(0)
+        .saturating_add(1);
+    if tries > BOOT_FAIL_MAX {
+        error!("New config did not bring up WiFi in {BOOT_FAIL_MAX} boots, reverting.");
+        good.to_nvs(nvs)?;
+        nvs.set_u8(CONFIG_TRIES_NAME, 0)?;
+        // the restored config gets its own boots before safe mode kicks in
+        clear_boot_count(nvs)?;
+        return Ok(good);
+    }
+
+    info!("New config not confirmed yet, boot {tries}/{BOOT_FAIL_MAX}.");
+    nvs.set_u8(CONFIG_TRIES_NAME, tries)?;
+    Ok(config)
+}
+
+/// True while the config read at boot has not brought up WiFi yet.
+pub fn config_on_probation(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> bool {
+    matches!(nvs.get_u8(CONFIG_TRIES_NAME), Ok(Some(tries)) if tries > 0)
+}
+
+/// Forget the known-good config, so that a factory reset cannot be reverted.
+pub fn clear_config_fallback(nvs: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+    MyConfig::remove_good_nvs(nvs)?;
+    nvs.set_u8(CONFIG_TRIES_NAME, 0)?;
+    Ok(())
+}
+
+/// Keep the running config as the known-good one once it has proven the network.
+pub async fn run_config_confirm(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    loop {
+        if *state.wifi_up.read().await {
+            break;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    let start = Instant::now();
+    let served = state.api_cnt.load(Ordering::Relaxed);
+    loop {
+        let config = state.config.read().await.clone();
+        let client_seen = state.api_cnt.load(Ordering::Relaxed) != served;
+        let mut check = config.confirm_check(client_seen, start.elapsed());
+        if check != ConfigCheck::Confirm && Box::pin(gateway_answers(&state)).await {
+            check = ConfigCheck::Confirm;
+        }
+
+        match check {
+            ConfigCheck::Confirm => break,
+            ConfigCheck::Wait => {}
+            ConfigCheck::Fail => {
+                if config_on_probation(&*state.nvs.read().await) {
+                    // let the boot fail, so that the config gets reverted
+                    error!("New config has not reached the network, resetting...");
+                    esp_idf_hal::reset::restart();
+                }
+            }
+        }
+        sleep(CONFIG_CONFIRM_RETRY).await;
+    }
+
+    if let Err(e) = confirm_config(&state).await {
+        // keep running, the config stays on probation
+        error!("Could not save config as known-good: {e:?}");
+    }
+
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+async fn gateway_answers(state: &MyState) -> bool {
+    match *state.ping_ip.read().await {
+        Some(ip) => check_ping(state, ip).await,
+        None => false,
+    }
+}
+
+async fn confirm_config(state: &MyState) -> anyhow::Result<()> {
+    let config = state.config.read().await.clone();
+    let mut nvs = state.nvs.write().await;
+    if MyConfig::good_from_nvs(&mut nvs).as_ref() != Some(&config) {
+        info!("WiFi is up, saving config as known-good.");
+        config.good_to_nvs(&mut nvs)?;
+    }
+    nvs.set_u8(CONFIG_TRIES_NAME, 0)?;
+    Ok(())
+}
+
+// EOF
@@ -10,6 +10,7 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     sync::{broadcast, mpsc},
+    time::{Instant, sleep_until},
 };
 
 use crate::*;
@@ -18,6 +19,11 @@ const BUFSZ: usize = 64;
 const CHANSZ: usize = 8;
 
 pub async fn run_serial(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    if state.safe_mode {
+        info!("Safe mode, serial bridge disabled.");
+        std::future::pending::<()>().await;
+    }
+
     info!("Waiting for WiFi...");
     loop {
         if *state.wifi_up.read().await {
@@ -31,13 +37,8 @@ pub async fn run_serial(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
 
     // create an mpsc channel for receiving serial port input from any client
     // mpsc = multi-producer, single consumer queue
-    let (ser_write_tx, ser_write_rx) = match state.config.serial_write_enabled {
-        true => {
-            let c = mpsc::channel(CHANSZ);
-            (Some(c.0), Some(c.1))
-        }
-        false => (None, None),
-    };
+    // writes are dropped in handle_serial() while serial write is disabled
+    let (ser_write_tx, ser_write_rx) = mpsc::channel(CHANSZ);
 
     let _ = tokio::try_join!(
         Box::pin(handle_network(
@@ -51,21 +52,73 @@ pub async fn run_serial(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
     Ok(())
 }
 
+// Serial settings that can be changed on the fly
+struct SerialSettings {
+    bps: u32,
+    data_bits: uart::config::DataBits,
+    parity: uart::config::Parity,
+    stop_bits: uart::config::StopBits,
+    write_enabled: bool,
+    pkt_size: usize,
+    pkt_timeout: Duration,
+}
+
+impl SerialSettings {
+    fn new(config: &MyConfig) -> anyhow::Result<Self> {
+        use esp_idf_hal::uart::config::*;
+
+        let (data_bits, parity, stop_bits) = match config.parse_framing() {
+            Ok(f) => f,
+            Err(e) => bail!(e),
+        };
+        Ok(Self {
+            bps: config.bps,
+            data_bits: match data_bits {
+                5 => DataBits::DataBits5,
+                6 => DataBits::DataBits6,
+                7 => DataBits::DataBits7,
+                _ => DataBits::DataBits8,
+            },
+            parity: match parity {
+                'E' => Parity::ParityEven,
+                'O' => Parity::ParityOdd,
+                _ => Parity::ParityNone,
+            },
+            stop_bits: match stop_bits {
+                2 => StopBits::STOP2,
+                _ => StopBits::STOP1,
+            },
+            write_enabled: config.serial_write_enabled,
+            pkt_size: config.serial_pkt_size.max(1) as usize,
+            pkt_timeout: Duration::from_millis(config.serial_pkt_timeout as u64),
+        })
+    }
+
+    /// Change the line settings of the running UART.
+    fn apply(&self, driver: &uart::UartDriver<'_>) -> anyhow::Result<()> {
+        driver.change_baudrate(Hertz(self.bps))?;
+        driver.change_data_bits(self.data_bits)?;
+        driver.change_parity(self.parity)?;
+        driver.change_stop_bits(self.stop_bits)?;
+        Ok(())
+    }
+}
+
 async fn handle_serial(
     state: Arc<Pin<Box<MyState>>>,
     ser_read_tx: broadcast::Sender<Vec<u8>>,
-    ser_write_rx: Option<mpsc::Receiver<Vec<u8>>>,
+    mut write_rx: mpsc::Receiver<Vec<u8>>,
 ) -> anyhow::Result<()> {
     info!("UART1 initialization...");
 
-    let bps = state.config.bps;
+    let mut settings = SerialSettings::new(&*state.config.read().await)?;
     use esp_idf_hal::uart::config::*;
-    let ser_config = Config::new()
+    let mut ser_config = Config::new()
         .flow_control(FlowControl::None)
-        .parity_none()
-        .data_bits(DataBits::DataBits8)
-        .stop_bits(StopBits::STOP1)
-        .baudrate(Hertz(bps));
+        .data_bits(settings.data_bits)
+        .stop_bits(settings.stop_bits)
+        .baudrate(Hertz(settings.bps));
+    ser_config.parity = settings.parity;
     info!("UART1 config:\n{ser_config:#?}");
 
     let my_ser = state.serial.write().await.take().unwrap();
@@ -80,17 +133,21 @@ async fn handle_serial(
     let mut led = PinDriver::output(my_ser.led)?;
     info!("UART1 opened.");
 
-    // create a dummy rx pair if we did not get one
-    let mut write_rx = ser_write_rx.unwrap_or_else(|| mpsc::channel(1).1);
+    // serial input is collected here until a packet is full or the line goes idle
+    let mut pkt: Vec<u8> = Vec::with_capacity(settings.pkt_size);
+    let mut pkt_deadline: Option<Instant> = None;
 
     let mut buf = [0; BUFSZ];
     loop {
         tokio::select! {
             Some(msg) = write_rx.recv() => {
-                led.toggle().ok();
-                // info!("serial write {} bytes", msg.len());
-                uart.write_all(msg.as_ref()).await?;
-
+                // the data read from tcp socket is thrown away unless serial write is enabled
+                if settings.write_enabled {
+                    led.toggle().ok();
+                    // info!("serial write {} bytes", msg.len());
+                    uart.write_all(msg.as_ref()).await?;
+                    state.serial_tx_bytes.fetch_add(msg.len() as u32, Ordering::Relaxed);
+                }
             }
 
             res = uart.read(&mut buf) => {
@@ -102,13 +159,55 @@ async fn handle_serial(
                     Ok(n) => {
                         led.toggle().ok();
                         // info!("Serial read {n} bytes.");
-                        ser_read_tx.send(buf[0..n].to_owned())?;
+                        state.serial_rx_bytes.fetch_add(n as u32, Ordering::Relaxed);
+                        pkt.extend_from_slice(&buf[0..n]);
+                        while pkt.len() >= settings.pkt_size {
+                            let rest = pkt.split_off(settings.pkt_size);
+                            ser_read_tx.send(std::mem::replace(&mut pkt, rest))?;
+                        }
+                        if pkt.is_empty() {
+                            pkt_deadline = None;
+                        } else if settings.pkt_timeout.is_zero() {
+                            ser_read_tx.send(std::mem::take(&mut pkt))?;
+                            pkt_deadline = None;
+                        } else {
+                            pkt_deadline = Some(Instant::now() + settings.pkt_timeout);
+                        }
                     }
                     Err(e) => {
                         bail!(e);
                     }
                 }
             }
+
+            _ = sleep_until(pkt_deadline.unwrap_or_else(Instant::now)), if pkt_deadline.is_some() => {
+                ser_read_tx.send(std::mem::take(&mut pkt))?;
+                pkt_deadline = None;
+            }
+
+            _ = state.serial_reconfig.notified() => {
+                let new = match SerialSettings::new(&*state.config.read().await) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("UART1 reconfig failed: {e:?}");
+                        continue;
+                    }
+                };
+                // a failed change must not end the bridge, it keeps running as before
+                if let Err(e) = new.apply(uart.driver()) {
+                    error!("UART1 reconfig failed, keeping the old settings: {e:?}");
+                    if let Err(e) = settings.apply(uart.driver()) {
+                        error!("UART1 could not restore the old settings: {e:?}");
+                    }
+                    continue;
+                }
+                info!(
+                    "UART1 reconfigured: {} bps {:?} {:?} {:?}, write {}, packet {} bytes / {:?}",
+                    new.bps, new.data_bits, new.parity, new.stop_bits,
+                    new.write_enabled, new.pkt_size, new.pkt_timeout,
+                );
+                settings = new;
+            }
         }
     }
     Ok(())
@@ -117,12 +216,12 @@ async fn handle_serial(
 async fn handle_network(
     state: Arc<Pin<Box<MyState>>>,
     ser_read_tx: broadcast::Sender<Vec<u8>>,
-    ser_write_tx: Option<mpsc::Sender<Vec<u8>>>,
+    ser_write_tx: mpsc::Sender<Vec<u8>>,
 ) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", state.config.serial_tcp_port)).await?;
+    let port = state.config.read().await.serial_tcp_port;
+    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await?;
     info!("Serial server listening...");
 
-    let write_enabled = ser_write_tx.is_some();
     loop {
         let stream = listener.accept().await;
         match stream {
@@ -132,15 +231,13 @@ async fn handle_network(
                 info!("Client #{cnt} connected from {}:{}", addr, addr.port());
                 let ser_read_rx = ser_read_tx.subscribe();
                 let ser_write_tx_c = ser_write_tx.clone();
+                let state_c = state.clone();
+                state.serial_clients.fetch_add(1, Ordering::Relaxed);
                 tokio::spawn(async move {
-                    Box::pin(handle_client(
-                        cnt,
-                        stream,
-                        ser_read_rx,
-                        ser_write_tx_c,
-                        write_enabled,
-                    ))
-                    .await
+                    let res =
+                        Box::pin(handle_client(cnt, stream, ser_read_rx, ser_write_tx_c)).await;
+                    state_c.serial_clients.fetch_sub(1, Ordering::Relaxed);
+                    res
                 });
             }
             Err(e) => {
@@ -156,8 +253,7 @@ async fn handle_client(
     c: u32,
     mut sock: TcpStream,
     mut ser_read_rx: broadcast::Receiver<Vec<u8>>,
-    ser_write_tx: Option<mpsc::Sender<Vec<u8>>>,
-    write_enabled: bool,
+    ser_write_tx: mpsc::Sender<Vec<u8>>,
 ) -> anyhow::Result<()> {
     let mut buf = [0; BUFSZ];
 
@@ -181,10 +277,7 @@ async fn handle_client(
                     info!("Client #{c} disconnected");
                     return Ok(());
                 }
-                // the data read from tcp sucket is thrown away unless serial write is enabled
-                if write_enabled {
-                    ser_write_tx.as_ref().unwrap().send(buf[0..n].to_owned()).await?;
-                }
+                ser_write_tx.send(buf[0..n].to_owned()).await?;
             }
         }
     }
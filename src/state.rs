@@ -1,7 +1,10 @@
 // state.rs
 
+use std::collections::VecDeque;
+
 use esp_idf_hal::{gpio::*, uart::UART1};
 use esp_idf_svc::nvs;
+use tokio::sync::Notify;
 
 use crate::*;
 
@@ -14,39 +17,74 @@ pub struct MySerial {
 unsafe impl Sync for MySerial {}
 
 pub struct MyState {
-    pub config: MyConfig,
+    pub config: RwLock<MyConfig>,
     pub ota_slot: String,
+    pub safe_mode: bool,
 
     pub api_cnt: AtomicU32,
+    pub serial_clients: AtomicU32,
+    pub serial_rx_bytes: AtomicU32,
+    pub serial_tx_bytes: AtomicU32,
     pub nvs: RwLock<nvs::EspNvs<nvs::NvsDefault>>,
     pub wifi_up: RwLock<bool>,
+    pub ap_mode: RwLock<bool>,
+    pub wifi_scan: RwLock<Vec<WifiScanEntry>>,
+    pub wifi_scan_req: Notify,
+    pub wifi_scan_done: Notify,
+    pub wifi_reset_req: Notify,
+    pub wifi_events: RwLock<VecDeque<WifiEventEntry>>,
     pub if_index: RwLock<u32>,
     pub ip_addr: RwLock<net::Ipv4Addr>,
     pub ping_ip: RwLock<Option<net::Ipv4Addr>>,
     pub myid: RwLock<String>,
+    pub auth_ok: RwLock<Option<String>>,
     pub restart: RwLock<bool>,
     pub serial: RwLock<Option<MySerial>>,
+    pub serial_reconfig: Notify,
+    pub mdns_refresh: Notify,
 }
 
 impl MyState {
     pub fn new(
         config: MyConfig,
         ota_slot: String,
+        safe_mode: bool,
         nvs: nvs::EspNvs<nvs::NvsDefault>,
         serial: MySerial,
     ) -> Self {
         MyState {
-            config,
+            config: RwLock::new(config),
             ota_slot,
+            safe_mode,
             api_cnt: 0.into(),
+            serial_clients: 0.into(),
+            serial_rx_bytes: 0.into(),
+            serial_tx_bytes: 0.into(),
             nvs: RwLock::new(nvs),
             wifi_up: RwLock::new(false),
+            ap_mode: RwLock::new(false),
+            wifi_scan: RwLock::new(Vec::new()),
+            wifi_scan_req: Notify::new(),
+            wifi_scan_done: Notify::new(),
+            wifi_reset_req: Notify::new(),
+            wifi_events: RwLock::new(VecDeque::new()),
             if_index: RwLock::new(0),
             ip_addr: RwLock::new(net::Ipv4Addr::new(0, 0, 0, 0)),
             ping_ip: RwLock::new(None),
-            myid: RwLock::new("esp32clock".into()),
+            myid: RwLock::new(String::new()),
+            auth_ok: RwLock::new(None),
             restart: RwLock::new(false),
             serial: RwLock::new(Some(serial)),
+            serial_reconfig: Notify::new(),
+            mdns_refresh: Notify::new(),
+        }
+    }
+
+    /// The configured hostname, or the device id if none is set.
+    pub async fn hostname(&self) -> String {
+        match self.config.read().await.hostname.as_str() {
+            "" => self.myid.read().await.clone(),
+            name => name.into(),
         }
     }
 }
@@ -0,0 +1,98 @@
+// status.rs
+
+use esp_idf_hal::reset::ResetReason;
+
+use crate::*;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MyVersion {
+    pub fw_version: String,
+    pub git_commit: String,
+    pub git_branch: String,
+    pub build_timestamp: String,
+    pub rustc_version: String,
+    pub hw_feature: String,
+    pub idf_version: String,
+}
+
+impl MyVersion {
+    pub fn get() -> Self {
+        let idf_version = unsafe { std::ffi::CStr::from_ptr(esp_idf_sys::esp_get_idf_version()) };
+
+        MyVersion {
+            fw_version: FW_VERSION.into(),
+            git_commit: GIT_COMMIT.unwrap_or("unknown").into(),
+            git_branch: GIT_BRANCH.unwrap_or("unknown").into(),
+            build_timestamp: SOURCE_TIMESTAMP.unwrap_or("unknown").into(),
+            rustc_version: RUSTC_VERSION.unwrap_or("unknown").into(),
+            hw_feature: HW_FEATURE.into(),
+            idf_version: idf_version.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MyStatus {
+    pub uptime: u64,
+    pub heap_free: u32,
+    pub heap_min_free: u32,
+
+    pub hostname: String,
+    pub wifi_ssid: String,
+    pub wifi_rssi: i8,
+    pub wifi_bssid: String,
+    pub wifi_channel: u8,
+    pub wifi_ap_mode: bool,
+    pub ip_addr: net::Ipv4Addr,
+
+    pub fw_version: String,
+    pub ota_slot: String,
+    pub reset_reason: String,
+    pub safe_mode: bool,
+
+    pub serial_clients: u32,
+    pub serial_rx_bytes: u32,
+    pub serial_tx_bytes: u32,
+}
+
+impl MyStatus {
+    pub async fn collect(state: &MyState) -> Self {
+        let (uptime, heap_free, heap_min_free) = unsafe {
+            (
+                (esp_idf_sys::esp_timer_get_time() / 1_000_000) as u64,
+                esp_idf_sys::esp_get_free_heap_size(),
+                esp_idf_sys::esp_get_minimum_free_heap_size(),
+            )
+        };
+
+        // all zeroes if we are not associated
+        let ap_info = sta_ap_info().unwrap_or_default();
+
+        MyStatus {
+            uptime,
+            heap_free,
+            heap_min_free,
+
+            hostname: state.hostname().await,
+            wifi_ssid: String::from_utf8_lossy(&ap_info.ssid)
+                .trim_end_matches('\0')
+                .into(),
+            wifi_rssi: ap_info.rssi,
+            wifi_bssid: format_bssid(&ap_info.bssid),
+            wifi_channel: ap_info.primary,
+            wifi_ap_mode: *state.ap_mode.read().await,
+            ip_addr: *state.ip_addr.read().await,
+
+            fw_version: FW_VERSION.into(),
+            ota_slot: state.ota_slot.clone(),
+            reset_reason: format!("{:?}", ResetReason::get()),
+            safe_mode: state.safe_mode,
+
+            serial_clients: state.serial_clients.load(Ordering::Relaxed),
+            serial_rx_bytes: state.serial_rx_bytes.load(Ordering::Relaxed),
+            serial_tx_bytes: state.serial_tx_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// EOF
@@ -0,0 +1,682 @@
+// validate.rs
+
+use crate::*;
+
+// limits of the ESP-IDF wifi_sta_config_t fields
+pub const WIFI_SSID_MAX_LEN: usize = 32;
+pub const WIFI_PASS_MAX_LEN: usize = 64;
+const WIFI_PSK_MIN_LEN: usize = 8;
+const WIFI_USERNAME_MAX_LEN: usize = 128;
+pub const WIFI_NETWORKS_MAX: usize = 5;
+const WIFI_CHANNEL_MAX: u8 = 14;
+const WIFI_ROAM_RSSI_RANGE: std::ops::RangeInclusive<i8> = -100..=-30;
+const WIFI_ROAM_PERIOD_MIN: u16 = 10;
+
+// esp_netif_set_hostname() rejects longer names (ESP_NETIF_HOSTNAME_MAX_SIZE)
+const HOSTNAME_MAX_LEN: usize = 32;
+const WATCHDOG_TARGETS_MAX: usize = 4;
+const WATCHDOG_INTERVAL_MIN: u32 = 10;
+
+pub const BPS_MIN: u32 = 300;
+pub const BPS_MAX: u32 = 5_000_000;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl MyConfig {
+    /// Check the whole config, returning every problem found keyed by field name.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        self.validate_wifi(&mut errors);
+        self.validate_hostname(&mut errors);
+        if !self.v4dhcp {
+            self.validate_ipv4(&mut errors);
+        }
+        self.validate_ports(&mut errors);
+        self.validate_serial(&mut errors);
+        self.validate_fw(&mut errors);
+        self.validate_watchdog(&mut errors);
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    fn validate_wifi(&self, errors: &mut Vec<FieldError>) {
+        // no networks keeps the device in access point mode
+        if self.wifi_networks.len() > WIFI_NETWORKS_MAX {
+            errors.push(FieldError::new(
+                "wifi_networks",
+                format!("At most {WIFI_NETWORKS_MAX} WiFi networks can be saved"),
+            ));
+        }
+
+        for (i, network) in self.wifi_networks.iter().enumerate() {
+            let field = |name: &str| format!("wifi_networks.{i}.{name}");
+
+            if network.ssid.is_empty() {
+                errors.push(FieldError::new(&field("ssid"), "SSID cannot be empty"));
+            } else if network.ssid.len() > WIFI_SSID_MAX_LEN {
+                errors.push(FieldError::new(
+                    &field("ssid"),
+                    format!("SSID cannot be longer than {WIFI_SSID_MAX_LEN} bytes"),
+                ));
+            } else if self.wifi_networks[..i]
+                .iter()
+                .any(|n| n.ssid == network.ssid)
+            {
+                errors.push(FieldError::new(&field("ssid"), "SSID is already listed"));
+            }
+
+            if network.pass == SECRET_MASK {
+                errors.push(FieldError::new(
+                    &field("pass"),
+                    "Password is not known for this SSID, enter it again",
+                ));
+            } else if network.pass.len() > WIFI_PASS_MAX_LEN {
+                errors.push(FieldError::new(
+                    &field("pass"),
+                    format!("Password cannot be longer than {WIFI_PASS_MAX_LEN} bytes"),
+                ));
+            } else if !network.wpa2ent {
+                match (network.auth, network.pass.is_empty()) {
+                    (WifiAuth::Open, false) => errors.push(FieldError::new(
+                        &field("pass"),
+                        "Open networks have no password",
+                    )),
+                    (WifiAuth::Wpa2 | WifiAuth::Wpa2Wpa3 | WifiAuth::Wpa3, true) => errors.push(
+                        FieldError::new(&field("pass"), "Password is required for WPA2 and WPA3"),
+                    ),
+                    (_, false) if network.pass.len() < WIFI_PSK_MIN_LEN => {
+                        errors.push(FieldError::new(
+                            &field("pass"),
+                            format!("WPA password must be at least {WIFI_PSK_MIN_LEN} characters"),
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+
+            if network.wpa2ent && network.username.trim().is_empty() {
+                errors.push(FieldError::new(
+                    &field("username"),
+                    "Username is required for WPA2-Enterprise",
+                ));
+            } else if network.username.len() > WIFI_USERNAME_MAX_LEN {
+                errors.push(FieldError::new(
+                    &field("username"),
+                    format!("Username cannot be longer than {WIFI_USERNAME_MAX_LEN} bytes"),
+                ));
+            }
+
+            if let Err(msg) = network.parse_bssid() {
+                errors.push(FieldError::new(&field("bssid"), msg));
+            }
+            if network.channel > WIFI_CHANNEL_MAX {
+                errors.push(FieldError::new(
+                    &field("channel"),
+                    format!("Channel must be between 1..{WIFI_CHANNEL_MAX}, or 0 for all"),
+                ));
+            }
+        }
+
+        if !WIFI_ROAM_RSSI_RANGE.contains(&self.wifi_roam_rssi) {
+            errors.push(FieldError::new(
+                "wifi_roam_rssi",
+                format!(
+                    "Roaming threshold must be between {}..{} dBm",
+                    WIFI_ROAM_RSSI_RANGE.start(),
+                    WIFI_ROAM_RSSI_RANGE.end()
+                ),
+            ));
+        }
+        if self.wifi_roam_period < WIFI_ROAM_PERIOD_MIN {
+            errors.push(FieldError::new(
+                "wifi_roam_period",
+                format!("Roaming period must be at least {WIFI_ROAM_PERIOD_MIN} seconds"),
+            ));
+        }
+    }
+
+    fn validate_hostname(&self, errors: &mut Vec<FieldError>) {
+        // empty uses the device id
+        let name = &self.hostname;
+        if name.len() > HOSTNAME_MAX_LEN {
+            errors.push(FieldError::new(
+                "hostname",
+                format!("Hostname cannot be longer than {HOSTNAME_MAX_LEN} characters"),
+            ));
+        } else if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            || name.starts_with('-')
+            || name.ends_with('-')
+        {
+            errors.push(FieldError::new(
+                "hostname",
+                "Hostname can only have letters, digits and inner hyphens",
+            ));
+        }
+    }
+
+    fn validate_ipv4(&self, errors: &mut Vec<FieldError>) {
+        if !(1..=30).contains(&self.v4mask) {
+            errors.push(FieldError::new("v4mask", "Mask bits must be between 1..30"));
+            return;
+        }
+        let mask = u32::MAX << (32 - self.v4mask);
+        let addr = u32::from(self.v4addr);
+        let network = addr & mask;
+        let broadcast = network | !mask;
+
+        if self.v4addr.is_unspecified()
+            || self.v4addr.is_loopback()
+            || self.v4addr.is_multicast()
+            || self.v4addr.is_broadcast()
+        {
+            errors.push(FieldError::new(
+                "v4addr",
+                format!("{} is not a usable host address", self.v4addr),
+            ));
+        } else if addr == network || addr == broadcast {
+            errors.push(FieldError::new(
+                "v4addr",
+                format!(
+                    "{} is the network or broadcast address of /{}",
+                    self.v4addr, self.v4mask
+                ),
+            ));
+        }
+
+        // no gateway is fine for a local-only network
+        if !self.v4gw.is_unspecified() {
+            let gw = u32::from(self.v4gw);
+            if gw & mask != network || gw == network || gw == broadcast {
+                errors.push(FieldError::new(
+                    "v4gw",
+                    format!(
+                        "Gateway {} is not a host in {}/{}",
+                        self.v4gw,
+                        net::Ipv4Addr::from(network),
+                        self.v4mask
+                    ),
+                ));
+            } else if gw == addr {
+                errors.push(FieldError::new(
+                    "v4gw",
+                    "Gateway cannot be the device's own address",
+                ));
+            }
+        }
+
+        for (field, dns) in [("dns1", self.dns1), ("dns2", self.dns2)] {
+            if dns.is_loopback() || dns.is_multicast() || dns.is_broadcast() {
+                errors.push(FieldError::new(
+                    field,
+                    format!("{dns} is not a usable DNS server"),
+                ));
+            }
+        }
+    }
+
+    fn validate_ports(&self, errors: &mut Vec<FieldError>) {
+        let mut ports = vec![
+            ("api_port", self.api_port),
+            ("serial_tcp_port", self.serial_tcp_port),
+        ];
+        if self.https_enabled {
+            ports.push(("https_port", self.https_port));
+        }
+
+        for (i, (name, port)) in ports.iter().enumerate() {
+            if *port == 0 {
+                errors.push(FieldError::new(name, "Port cannot be 0"));
+                continue;
+            }
+            if self.https_enabled && *port == HTTPS_PROXY_PORT {
+                errors.push(FieldError::new(
+                    name,
+                    format!("Port {port} is reserved for the HTTPS proxy"),
+                ));
+                continue;
+            }
+            if let Some((other, _)) = ports[..i].iter().find(|(_, p)| p == port) {
+                errors.push(FieldError::new(
+                    name,
+                    format!("Port {port} collides with {other}"),
+                ));
+            }
+        }
+    }
+
+    fn validate_serial(&self, errors: &mut Vec<FieldError>) {
+        if !(BPS_MIN..=BPS_MAX).contains(&self.bps) {
+            errors.push(FieldError::new(
+                "bps",
+                format!("Baud rate must be between {BPS_MIN}..{BPS_MAX}"),
+            ));
+        }
+        if let Err(msg) = self.parse_framing() {
+            errors.push(FieldError::new("serial_framing", msg));
+        }
+        if self.serial_pkt_size == 0 || self.serial_pkt_size > SERIAL_PKT_SIZE_MAX {
+            errors.push(FieldError::new(
+                "serial_pkt_size",
+                format!("Packet size must be between 1..{SERIAL_PKT_SIZE_MAX}"),
+            ));
+        }
+    }
+
+    fn validate_fw(&self, errors: &mut Vec<FieldError>) {
+        let url = &self.fw_manifest_url;
+        if !url.is_empty() && !url.starts_with("http://") && !url.starts_with("https://") {
+            errors.push(FieldError::new(
+                "fw_manifest_url",
+                "Manifest URL must start with http:// or https://",
+            ));
+        }
+    }
+
+    fn validate_watchdog(&self, errors: &mut Vec<FieldError>) {
+        if self.watchdog_check == WatchdogCheck::Off {
+            return;
+        }
+        if self.watchdog_interval < WATCHDOG_INTERVAL_MIN {
+            errors.push(FieldError::new(
+                "watchdog_interval",
+                format!("Check interval must be at least {WATCHDOG_INTERVAL_MIN} seconds"),
+            ));
+        }
+        if self.watchdog_failures == 0 {
+            errors.push(FieldError::new(
+                "watchdog_failures",
+                "Failure count must be at least 1",
+            ));
+        }
+
+        let targets = &self.watchdog_targets;
+        if targets.len() > WATCHDOG_TARGETS_MAX {
+            errors.push(FieldError::new(
+                "watchdog_targets",
+                format!("At most {WATCHDOG_TARGETS_MAX} targets can be checked"),
+            ));
+            return;
+        }
+        if targets.is_empty() && self.watchdog_check != WatchdogCheck::Ping {
+            errors.push(FieldError::new(
+                "watchdog_targets",
+                "Targets are needed for TCP and DNS checks",
+            ));
+        }
+        for target in targets {
+            let bad = match self.watchdog_check {
+                WatchdogCheck::Off => false,
+                WatchdogCheck::Ping => target.parse::<net::Ipv4Addr>().is_err(),
+                WatchdogCheck::Tcp => match target.rsplit_once(':') {
+                    Some((host, port)) => {
+                        host.is_empty() || !port.parse::<u16>().is_ok_and(|p| p > 0)
+                    }
+                    None => true,
+                },
+                WatchdogCheck::Dns => target.is_empty() || target.contains(char::is_whitespace),
+            };
+            if bad {
+                let expected = match self.watchdog_check {
+                    WatchdogCheck::Tcp => "host:port",
+                    WatchdogCheck::Dns => "a host name",
+                    _ => "an IPv4 address",
+                };
+                errors.push(FieldError::new(
+                    "watchdog_targets",
+                    format!("Target {target:?} must be {expected}"),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(config: &MyConfig) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => vec![],
+            Err(errors) => errors.into_iter().map(|e| e.field).collect(),
+        }
+    }
+
+    fn static_ip() -> MyConfig {
+        MyConfig {
+            v4dhcp: false,
+            v4addr: net::Ipv4Addr::new(192, 168, 1, 10),
+            v4mask: 24,
+            v4gw: net::Ipv4Addr::new(192, 168, 1, 1),
+            dns1: net::Ipv4Addr::new(192, 168, 1, 1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(MyConfig::default().validate(), Ok(()));
+        assert_eq!(static_ip().validate(), Ok(()));
+    }
+
+    #[test]
+    fn wifi_lengths() {
+        let config = MyConfig {
+            wifi_networks: vec![test_network(
+                &"x".repeat(WIFI_SSID_MAX_LEN + 1),
+                &"x".repeat(WIFI_PASS_MAX_LEN + 1),
+                0,
+            )],
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(&config),
+            ["wifi_networks.0.ssid", "wifi_networks.0.pass"]
+        );
+
+        let config = MyConfig {
+            wifi_networks: vec![
+                test_network("office", "hunter22", 0),
+                test_network("", "short", 0),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(&config),
+            ["wifi_networks.1.ssid", "wifi_networks.1.pass"]
+        );
+
+        let config = MyConfig {
+            wifi_networks: vec![test_network(&"x".repeat(WIFI_SSID_MAX_LEN), "", 0)],
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn wifi_network_list() {
+        let config = MyConfig {
+            wifi_networks: vec![
+                test_network("office", "hunter22", 0),
+                test_network("office", "", 0),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["wifi_networks.1.ssid"]);
+
+        let config = MyConfig {
+            wifi_networks: vec![test_network("office", SECRET_MASK, 0)],
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["wifi_networks.0.pass"]);
+
+        let config = MyConfig {
+            wifi_networks: (0..=WIFI_NETWORKS_MAX)
+                .map(|i| test_network(&format!("net{i}"), "", 0))
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["wifi_networks"]);
+    }
+
+    #[test]
+    fn wpa2ent_needs_username() {
+        let mut config = MyConfig {
+            wifi_networks: vec![WifiNetwork {
+                wpa2ent: true,
+                ..test_network("corp", "short", 0)
+            }],
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["wifi_networks.0.username"]);
+        config.wifi_networks[0].username = "user".into();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn auth_and_password() {
+        let with_auth = |auth, pass| MyConfig {
+            wifi_networks: vec![WifiNetwork {
+                auth,
+                ..test_network("home", pass, 0)
+            }],
+            ..Default::default()
+        };
+        assert_eq!(with_auth(WifiAuth::Auto, "").validate(), Ok(()));
+        assert_eq!(with_auth(WifiAuth::Wpa3, "hunter22").validate(), Ok(()));
+        assert_eq!(with_auth(WifiAuth::Open, "").validate(), Ok(()));
+        for config in [
+            with_auth(WifiAuth::Open, "hunter22"),
+            with_auth(WifiAuth::Wpa2Wpa3, ""),
+            with_auth(WifiAuth::Wpa3, "short"),
+        ] {
+            assert_eq!(fields(&config), ["wifi_networks.0.pass"]);
+        }
+    }
+
+    #[test]
+    fn bssid_and_roaming() {
+        let with_bssid = |bssid: &str, channel| MyConfig {
+            wifi_networks: vec![WifiNetwork {
+                bssid: bssid.into(),
+                channel,
+                ..test_network("office", "hunter22", 0)
+            }],
+            ..Default::default()
+        };
+        let config = with_bssid("A4:2b:b0:12:34:56", 6);
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(
+            config.wifi_networks[0].parse_bssid(),
+            Ok(Some([0xa4, 0x2b, 0xb0, 0x12, 0x34, 0x56]))
+        );
+        for bssid in [
+            "a4:2b:b0:12:34",
+            "a4:2b:b0:12:34:56:78",
+            "a42bb0123456",
+            "a4:2b:b0:12:34:5g",
+            "a4:2b:b0:12:34:+6",
+        ] {
+            assert_eq!(fields(&with_bssid(bssid, 0)), ["wifi_networks.0.bssid"]);
+        }
+        assert_eq!(fields(&with_bssid("", 15)), ["wifi_networks.0.channel"]);
+
+        let config = MyConfig {
+            wifi_roam_rssi: -20,
+            wifi_roam_period: 5,
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["wifi_roam_rssi", "wifi_roam_period"]);
+    }
+
+    #[test]
+    fn hostname_chars() {
+        for name in ["", "serial-gw-3", "ESP32", &"x".repeat(HOSTNAME_MAX_LEN)] {
+            let config = MyConfig {
+                hostname: name.into(),
+                ..Default::default()
+            };
+            assert_eq!(config.validate(), Ok(()), "{name}");
+        }
+        for name in [
+            "-gw",
+            "gw-",
+            "gw.local",
+            "serial gw",
+            "gw_1",
+            &"x".repeat(HOSTNAME_MAX_LEN + 1),
+        ] {
+            let config = MyConfig {
+                hostname: name.into(),
+                ..Default::default()
+            };
+            assert_eq!(fields(&config), ["hostname"], "{name}");
+        }
+    }
+
+    #[test]
+    fn static_ip_consistency() {
+        let config = MyConfig {
+            v4mask: 31,
+            ..static_ip()
+        };
+        assert_eq!(fields(&config), ["v4mask"]);
+
+        let config = MyConfig {
+            v4addr: net::Ipv4Addr::new(192, 168, 1, 255),
+            ..static_ip()
+        };
+        assert_eq!(fields(&config), ["v4addr"]);
+
+        let config = MyConfig {
+            v4gw: net::Ipv4Addr::new(10, 0, 0, 1),
+            ..static_ip()
+        };
+        assert_eq!(fields(&config), ["v4gw"]);
+
+        let config = MyConfig {
+            v4gw: net::Ipv4Addr::new(192, 168, 1, 10),
+            ..static_ip()
+        };
+        assert_eq!(fields(&config), ["v4gw"]);
+
+        let config = MyConfig {
+            v4gw: net::Ipv4Addr::UNSPECIFIED,
+            ..static_ip()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn dhcp_ignores_static_fields() {
+        let config = MyConfig {
+            v4dhcp: true,
+            v4mask: 31,
+            ..static_ip()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn port_collisions() {
+        let config = MyConfig {
+            api_port: 0,
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["api_port"]);
+
+        let config = MyConfig {
+            serial_tcp_port: DEFAULT_API_PORT,
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["serial_tcp_port"]);
+
+        let config = MyConfig {
+            https_enabled: true,
+            https_port: DEFAULT_API_PORT,
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["https_port"]);
+
+        let config = MyConfig {
+            https_enabled: true,
+            api_port: HTTPS_PROXY_PORT,
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["api_port"]);
+
+        // the proxy port is free while HTTPS is off
+        let config = MyConfig {
+            api_port: HTTPS_PROXY_PORT,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn serial_settings() {
+        let config = MyConfig {
+            bps: BPS_MIN - 1,
+            serial_framing: "9N1".into(),
+            serial_pkt_size: SERIAL_PKT_SIZE_MAX + 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(&config),
+            ["bps", "serial_framing", "serial_pkt_size"]
+        );
+
+        let config = MyConfig {
+            bps: BPS_MAX,
+            serial_framing: "7e2".into(),
+            serial_pkt_size: 1,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn manifest_url_scheme() {
+        let config = MyConfig {
+            fw_manifest_url: "ftp://example.com/manifest.json".into(),
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["fw_manifest_url"]);
+    }
+
+    #[test]
+    fn watchdog_targets() {
+        let config = MyConfig {
+            watchdog_targets: vec!["192.168.1.1".into(), "example.com".into()],
+            watchdog_interval: 5,
+            watchdog_failures: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(&config),
+            ["watchdog_interval", "watchdog_failures", "watchdog_targets"]
+        );
+
+        let config = MyConfig {
+            watchdog_check: WatchdogCheck::Tcp,
+            watchdog_targets: vec![
+                "example.com:443".into(),
+                "example.com".into(),
+                ":80".into(),
+                "example.com:0".into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["watchdog_targets"; 3]);
+
+        let config = MyConfig {
+            watchdog_check: WatchdogCheck::Dns,
+            ..Default::default()
+        };
+        assert_eq!(fields(&config), ["watchdog_targets"]);
+
+        let config = MyConfig {
+            watchdog_check: WatchdogCheck::Off,
+            watchdog_interval: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
+
+// EOF
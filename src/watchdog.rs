@@ -0,0 +1,175 @@
+// watchdog.rs
+
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use esp_idf_svc::ping;
+use tokio::net::TcpStream;
+
+use crate::*;
+
+const WATCHDOG_TCP_TIMEOUT: Duration = Duration::from_secs(5);
+const WATCHDOG_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check the configured targets and recover the network once they keep failing.
+pub async fn run_watchdog(state: Arc<Pin<Box<MyState>>>) -> anyhow::Result<()> {
+    let config = state.config.read().await.clone();
+    if config.watchdog_check == WatchdogCheck::Off || state.safe_mode {
+        info!("Connectivity watchdog disabled.");
+        std::future::pending::<()>().await;
+    }
+
+    let mut failures = 0;
+    loop {
+        sleep(Duration::from_secs(config.watchdog_interval as u64)).await;
+        if !*state.wifi_up.read().await || *state.ap_mode.read().await {
+            continue;
+        }
+
+        if Box::pin(check_targets(&state, &config)).await {
+            failures = 0;
+            continue;
+        }
+        failures += 1;
+        error!(
+            "Connectivity check failed ({failures}/{}).",
+            config.watchdog_failures
+        );
+        if failures < config.watchdog_failures {
+            continue;
+        }
+        failures = 0;
+
+        match config.watchdog_action {
+            WatchdogAction::Reconnect | WatchdogAction::RestartNet => {
+                error!("Watchdog resetting WiFi ({:?}).", config.watchdog_action);
+                // kept until the WiFi loop is back waiting for requests
+                state.wifi_reset_req.notify_one();
+            }
+            WatchdogAction::Reboot => {
+                error!("Watchdog rebooting.");
+                sleep(Duration::from_millis(2000)).await;
+                requested_restart(&state).await;
+            }
+        }
+    }
+}
+
+/// True if any of the targets answers. Without targets, the gateway is pinged.
+async fn check_targets(state: &MyState, config: &MyConfig) -> bool {
+    if config.watchdog_targets.is_empty() {
+        return match *state.ping_ip.read().await {
+            Some(ip) => check_ping(state, ip).await,
+            None => {
+                error!("No gateway to ping.");
+                false
+            }
+        };
+    }
+
+    for target in &config.watchdog_targets {
+        let ok = match config.watchdog_check {
+            WatchdogCheck::Off => true,
+            WatchdogCheck::Ping => match target.parse() {
+                Ok(ip) => check_ping(state, ip).await,
+                Err(e) => {
+                    error!("Bad ping target {target:?}: {e}");
+                    false
+                }
+            },
+            WatchdogCheck::Tcp => check_tcp(target).await,
+            WatchdogCheck::Dns => check_dns(target).await,
+        };
+        if ok {
+            return true;
+        }
+    }
+    false
+}
+
+pub async fn check_ping(state: &MyState, ip: net::Ipv4Addr) -> bool {
+    let if_idx = *state.if_index.read().await;
+    if if_idx == 0 {
+        error!("No if_index. wat?");
+        return false;
+    }
+
+    info!("Starting ping {ip} (if_idx {if_idx})");
+    let conf = ping::Configuration {
+        count: 2,
+        interval: Duration::from_millis(500),
+        timeout: Duration::from_millis(200),
+        data_size: 64,
+        tos: 0,
+    };
+    match task::spawn_blocking(move || ping::EspPing::new(if_idx).ping(ip, &conf)).await {
+        Ok(Ok(res)) => {
+            info!("Ping result: {res:?}");
+            res.received > 0
+        }
+        Ok(Err(e)) => {
+            error!("Ping {ip} failed: {e:?}");
+            false
+        }
+        Err(e) => {
+            error!("Ping {ip} task failed: {e:?}");
+            false
+        }
+    }
+}
+
+/// Look up `addr`, giving up after `WATCHDOG_LOOKUP_TIMEOUT`.
+async fn resolve<A>(addr: A) -> anyhow::Result<Vec<SocketAddr>>
+where
+    A: ToSocketAddrs + Send + 'static,
+{
+    let lookup = task::spawn_blocking(move || addr.to_socket_addrs().map(Iterator::collect));
+    match tokio::time::timeout(WATCHDOG_LOOKUP_TIMEOUT, lookup).await {
+        Ok(res) => Ok(res??),
+        Err(_) => bail!("timed out"),
+    }
+}
+
+async fn check_tcp(target: &str) -> bool {
+    let addrs = match resolve(target.to_string()).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            error!("Cannot resolve {target}: {e}");
+            return false;
+        }
+    };
+    match tokio::time::timeout(WATCHDOG_TCP_TIMEOUT, TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(_)) => {
+            info!("TCP connect to {target} ok");
+            true
+        }
+        Ok(Err(e)) => {
+            error!("TCP connect to {target} failed: {e}");
+            false
+        }
+        Err(_) => {
+            error!("TCP connect to {target} timed out");
+            false
+        }
+    }
+}
+
+async fn check_dns(target: &str) -> bool {
+    match resolve((target.to_string(), 0)).await {
+        Ok(addrs) => match addrs.first() {
+            Some(addr) => {
+                info!("DNS lookup {target}: {}", addr.ip());
+                true
+            }
+            None => {
+                error!("DNS lookup {target}: no addresses");
+                false
+            }
+        },
+        Err(e) => {
+            error!("DNS lookup {target} failed: {e}");
+            false
+        }
+    }
+}
+
+// EOF
@@ -1,20 +1,65 @@
 // wifi.rs
 
-use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use std::{ffi::CString, time::Instant};
+
+#[cfg(target_os = "espidf")]
+use embedded_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration,
+};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::{
     eventloop::{EspEventLoop, System},
+    handle::RawHandle,
     ipv4, netif,
     timer::{EspTimerService, Task},
-    wifi::{AsyncWifi, EspWifi, WifiDriver},
+    wifi::{AsyncWifi, EspWifi, WifiDriver, WifiEvent},
 };
 
 use crate::*;
 
+pub const WIFI_AP_IP: net::Ipv4Addr = net::Ipv4Addr::new(192, 168, 71, 1);
+const WIFI_CONNECT_TRIES: u32 = 3;
+const WIFI_AP_STA_RETRY: Duration = Duration::from_secs(300);
+const WIFI_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const WIFI_RETRY_DELAY: Duration = Duration::from_secs(5);
+const WIFI_RETRY_DELAY_MAX: Duration = Duration::from_secs(300);
+const WIFI_EVENTS_MAX: usize = 32;
+pub const WIFI_SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+const WIFI_ROAM_CHECK: Duration = Duration::from_secs(10);
+// a roaming target must be this much stronger than the current access point
+const WIFI_ROAM_MIN_GAIN: i8 = 8;
+
+/// One access point seen in a scan.
+#[derive(Clone, Debug, Serialize)]
+pub struct WifiScanEntry {
+    pub ssid: String,
+    pub bssid: String,
+    pub channel: u8,
+    pub rssi: i8,
+    pub auth: String,
+}
+
+/// A station disconnect reported by the WiFi driver, kept for diagnostics.
+#[derive(Clone, Debug, Serialize)]
+pub struct WifiEventEntry {
+    /// Milliseconds since boot.
+    pub uptime_ms: u64,
+    pub ssid: String,
+    pub bssid: String,
+    pub rssi: i8,
+    pub reason: u16,
+    pub reason_name: &'static str,
+}
+
+#[cfg(target_os = "espidf")]
 pub struct WifiLoop<'a> {
     pub state: Arc<std::pin::Pin<Box<MyState>>>,
     pub wifi: Option<AsyncWifi<EspWifi<'a>>>,
+    // the supplicant keeps pointers to these, they live as long as the loop
+    pub eap_certs: EapCerts,
 }
 
+#[cfg(target_os = "espidf")]
 impl<'a> WifiLoop<'a> {
     pub async fn run(
         mut self,
@@ -24,17 +69,18 @@ impl<'a> WifiLoop<'a> {
     ) -> anyhow::Result<()> {
         info!("Initializing Wi-Fi...");
 
-        let ipv4_config = if self.state.config.v4dhcp {
+        let config = self.state.config.read().await.clone();
+        let ipv4_config = if config.v4dhcp {
             ipv4::ClientConfiguration::DHCP(ipv4::DHCPClientSettings::default())
         } else {
             ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
-                ip: self.state.config.v4addr,
+                ip: config.v4addr,
                 subnet: ipv4::Subnet {
-                    gateway: self.state.config.v4gw,
-                    mask: ipv4::Mask(self.state.config.v4mask),
+                    gateway: config.v4gw,
+                    mask: ipv4::Mask(config.v4mask),
                 },
-                dns: Some(self.state.config.dns1),
-                secondary_dns: Some(self.state.config.dns2),
+                dns: Some(config.dns1),
+                secondary_dns: Some(config.dns2),
             })
         };
         // info!("IP config: {ipv4_config:?}");
@@ -49,51 +95,135 @@ impl<'a> WifiLoop<'a> {
             mac[0], mac[1], mac[2], mac[3], mac[4], mac[5],
         );
 
-        let espwifi = EspWifi::wrap_all(
-            wifidriver,
-            net_if,
-            netif::EspNetif::new(netif::NetifStack::Ap)?,
-        )?;
+        // sent with DHCP requests, so it shows up in leases and DNS
+        let hostname = self.state.hostname().await;
+        let c_hostname = CString::new(hostname.as_str())?;
+        esp_idf_sys::esp!(unsafe {
+            esp_idf_sys::esp_netif_set_hostname(net_if.handle(), c_hostname.as_ptr())
+        })?;
+        info!("Hostname {hostname}");
+
+        // access point clients get us as their DNS server, for the captive portal
+        let ap_if = netif::EspNetif::new_with_conf(&netif::NetifConfiguration {
+            ip_configuration: Some(ipv4::Configuration::Router(ipv4::RouterConfiguration {
+                subnet: ipv4::Subnet {
+                    gateway: WIFI_AP_IP,
+                    mask: ipv4::Mask(24),
+                },
+                dhcp_enabled: true,
+                dns: Some(WIFI_AP_IP),
+                secondary_dns: Some(WIFI_AP_IP),
+            })),
+            ..netif::NetifConfiguration::wifi_default_router()
+        })?;
+
+        match EapCerts::from_nvs(&mut *self.state.nvs.write().await) {
+            Ok(certs) => self.eap_certs = certs,
+            Err(e) => error!("Cannot read EAP certificates from nvs: {e:?}"),
+        }
+
+        // record disconnects, including failed connection attempts
+        let events_state = self.state.clone();
+        let _events = sysloop.subscribe::<WifiEvent, _>(move |event| {
+            if let WifiEvent::StaDisconnected(disconnected) = event {
+                let entry = WifiEventEntry {
+                    uptime_ms: unsafe { esp_idf_sys::esp_timer_get_time() } as u64 / 1000,
+                    ssid: String::from_utf8_lossy(disconnected.ssid()).into(),
+                    bssid: format_bssid(&disconnected.bssid()),
+                    rssi: disconnected.rssi(),
+                    reason: disconnected.reason(),
+                    reason_name: disconnect_reason(disconnected.reason()),
+                };
+                info!("WiFi event: {entry:?}");
+                // the event loop runs in its own task, outside of the async runtime
+                let mut events = events_state.wifi_events.blocking_write();
+                if events.len() >= WIFI_EVENTS_MAX {
+                    events.pop_front();
+                }
+                events.push_back(entry);
+            }
+        })?;
+
+        let espwifi = EspWifi::wrap_all(wifidriver, net_if, ap_if)?;
         self.wifi = Some(AsyncWifi::wrap(espwifi, sysloop, timer.clone())?);
         Box::pin(self.configure()).await?;
 
-        if let Err(e) = Box::pin(self.initial_connect()).await {
-            error!("WiFi connection failed: {e:?}");
-            error!("Resetting...");
-            sleep(Duration::from_secs(5)).await;
-            esp_idf_hal::reset::restart();
+        let mut connected = false;
+        if config.wifi_networks.is_empty() {
+            info!("WiFi not configured.");
+        } else {
+            for attempt in 1..=WIFI_CONNECT_TRIES {
+                match Box::pin(self.connect_best()).await {
+                    Ok(_) => {
+                        connected = true;
+                        break;
+                    }
+                    Err(e) => {
+                        error!("WiFi connection {attempt}/{WIFI_CONNECT_TRIES} failed: {e:?}");
+                    }
+                }
+                if attempt < WIFI_CONNECT_TRIES {
+                    let delay = retry_delay(attempt - 1);
+                    info!("WiFi retrying in {}s...", delay.as_secs());
+                    sleep(delay).await;
+                }
+            }
+        }
+
+        if !connected {
+            // let a new config fail the boot, so that it gets reverted
+            if !config.wifi_networks.is_empty()
+                && config_on_probation(&*self.state.nvs.read().await)
+            {
+                error!("Resetting...");
+                sleep(Duration::from_secs(5)).await;
+                esp_idf_hal::reset::restart();
+            }
+            Box::pin(self.access_point()).await?;
         }
 
         sleep(Duration::from_secs(5)).await;
-
-        let netif = self.wifi.as_ref().unwrap().wifi().sta_netif();
-        let ip_info = netif.get_ip_info()?;
-        *self.state.if_index.write().await = netif.get_index();
-        *self.state.ip_addr.write().await = ip_info.ip;
-        *self.state.ping_ip.write().await = Some(ip_info.subnet.gateway);
+        self.update_ip_info().await?;
         *self.state.wifi_up.write().await = true;
 
         Box::pin(self.stay_connected()).await
     }
 
     pub async fn configure(&mut self) -> anyhow::Result<()> {
-        info!("WiFi setting credentials...");
         let wifi = self.wifi.as_mut().unwrap();
-        let config = &self.state.config;
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+
+        info!("WiFi driver starting...");
+        Ok(Box::pin(wifi.start()).await?)
+    }
+
+    /// Set the station credentials of one network, keeping our access point up if it is.
+    fn set_client_config(&mut self, network: &WifiNetwork) -> anyhow::Result<()> {
+        info!("WiFi setting credentials for {}...", network.ssid);
         let mut client_cfg = ClientConfiguration {
-            ssid: config.wifi_ssid.as_str().try_into().unwrap(),
+            ssid: network.ssid.as_str().try_into().unwrap_or_default(),
+            bssid: network.parse_bssid().ok().flatten(),
+            channel: (network.channel != 0).then_some(network.channel),
             ..Default::default()
         };
-        if config.wifi_pass.is_empty() {
-            client_cfg.auth_method = AuthMethod::None;
-        } else {
-            client_cfg.auth_method = AuthMethod::WPA2Personal;
-            client_cfg.password = config.wifi_pass.as_str().try_into().unwrap();
+        // the auth method is the weakest one accepted from the access point
+        client_cfg.auth_method = match (network.auth, network.pass.is_empty()) {
+            (WifiAuth::Auto, true) | (WifiAuth::Open, _) => AuthMethod::None,
+            (WifiAuth::Auto, false) | (WifiAuth::Wpa2, _) => AuthMethod::WPA2Personal,
+            (WifiAuth::Wpa2Wpa3, _) => AuthMethod::WPA2WPA3Personal,
+            (WifiAuth::Wpa3, _) => AuthMethod::WPA3Personal,
+        };
+        if client_cfg.auth_method != AuthMethod::None {
+            client_cfg.password = network.pass.as_str().try_into().unwrap_or_default();
         }
-        if config.wifi_wpa2ent {
+        if network.wpa2ent {
             client_cfg.auth_method = AuthMethod::WPA2Enterprise;
-            let username = config.wifi_username.as_str();
-            let password = config.wifi_pass.as_str();
+            let username = network.username.as_str();
+            let password = network.pass.as_str();
+            let pem = |pem: &Option<CString>| {
+                pem.as_ref()
+                    .map(|p| (p.as_ptr().cast::<u8>(), p.as_bytes_with_nul().len() as i32))
+            };
             unsafe {
                 esp_idf_sys::esp_eap_client_clear_ca_cert();
                 esp_idf_sys::esp_eap_client_clear_certificate_and_key();
@@ -101,76 +231,501 @@ impl<'a> WifiLoop<'a> {
                 esp_idf_sys::esp_eap_client_clear_username();
                 esp_idf_sys::esp_eap_client_clear_password();
                 esp_idf_sys::esp_eap_client_clear_new_password();
-                // let ret1 = esp_idf_sys::esp_eap_client_set_username(username.as_ptr(), username.len() as i32);
                 let ret1 = esp_idf_sys::esp_eap_client_set_identity(
                     username.as_ptr(),
                     username.len() as i32,
                 );
-                let ret2 = esp_idf_sys::esp_eap_client_set_username(
-                    username.as_ptr(),
-                    username.len() as i32,
-                );
-                let ret3 = esp_idf_sys::esp_eap_client_set_password(
-                    password.as_ptr(),
-                    password.len() as i32,
-                );
-                // let ret4 = esp_idf_sys::esp_eap_client_set_new_password(password.as_ptr(), password.len() as i32);
+                let ret2 = match network.eap_method {
+                    EapMethod::Peap => {
+                        esp_idf_sys::esp_eap_client_set_username(
+                            username.as_ptr(),
+                            username.len() as i32,
+                        );
+                        esp_idf_sys::esp_eap_client_set_password(
+                            password.as_ptr(),
+                            password.len() as i32,
+                        )
+                    }
+                    EapMethod::Tls => match (pem(&self.eap_certs.cert), pem(&self.eap_certs.key)) {
+                        (Some((cert, cert_len)), Some((key, key_len))) => {
+                            esp_idf_sys::esp_eap_client_set_certificate_and_key(
+                                cert,
+                                cert_len,
+                                key,
+                                key_len,
+                                core::ptr::null(),
+                                0,
+                            )
+                        }
+                        _ => {
+                            error!(
+                                "WiFi EAP-TLS needs a client certificate, upload one to /wifi/certs"
+                            );
+                            esp_idf_sys::ESP_ERR_INVALID_STATE as i32
+                        }
+                    },
+                };
+                let ret3 = match pem(&self.eap_certs.ca) {
+                    Some((ca, ca_len)) => esp_idf_sys::esp_eap_client_set_ca_cert(ca, ca_len),
+                    None => {
+                        warn!("WiFi enterprise server not validated, no CA certificate");
+                        0
+                    }
+                };
                 let ret4 = esp_idf_sys::esp_wifi_sta_enterprise_enable();
 
-                info!("WiFi WPA2 Enterprise: {ret1}:{ret2}:{ret3}:{ret4}");
+                info!(
+                    "WiFi enterprise {:?}: {ret1}:{ret2}:{ret3}:{ret4}",
+                    network.eap_method
+                );
+            }
+        } else {
+            // a previous network may have been enterprise
+            unsafe {
+                esp_idf_sys::esp_wifi_sta_enterprise_disable();
             }
         }
-        wifi.set_configuration(&Configuration::Client(client_cfg))?;
 
-        info!("WiFi driver starting...");
-        Ok(Box::pin(wifi.start()).await?)
+        let wifi = self.wifi.as_mut().unwrap();
+        let cfg = match wifi.get_configuration()? {
+            Configuration::Mixed(_, ap_cfg) => Configuration::Mixed(client_cfg, ap_cfg),
+            _ => Configuration::Client(client_cfg),
+        };
+        wifi.set_configuration(&cfg)?;
+
+        // esp-idf-svc always clears the PMF flags, which rules out WPA3, so set them here
+        let mut sta_cfg = esp_idf_sys::wifi_config_t::default();
+        unsafe {
+            esp_idf_sys::esp!(esp_idf_sys::esp_wifi_get_config(
+                esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+                &mut sta_cfg
+            ))?;
+            sta_cfg.sta.pmf_cfg.capable = true;
+            sta_cfg.sta.pmf_cfg.required = network.pmf == WifiPmf::Required;
+            sta_cfg.sta.sae_pwe_h2e = esp_idf_sys::wifi_sae_pwe_method_t_WPA3_SAE_PWE_BOTH;
+            esp_idf_sys::esp!(esp_idf_sys::esp_wifi_set_config(
+                esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+                &mut sta_cfg
+            ))?;
+        }
+        Ok(())
     }
 
-    pub async fn initial_connect(&mut self) -> anyhow::Result<()> {
-        self.do_connect_loop(true).await
+    /// Connect to the best known network in range, falling through to the next on failure.
+    async fn connect_best(&mut self) -> anyhow::Result<()> {
+        let networks = self.state.config.read().await.wifi_networks.clone();
+
+        let seen = Box::pin(self.scan()).await;
+        for network in rank_networks(&networks, &seen) {
+            match Box::pin(self.connect_network(network)).await {
+                Ok(_) => {
+                    info!("WiFi connected to {}.", network.ssid);
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("WiFi connection to {} failed: {e:?}", network.ssid);
+                    self.wifi.as_mut().unwrap().wifi_mut().disconnect().ok();
+                }
+            }
+        }
+        bail!("No known WiFi network available")
     }
 
-    pub async fn stay_connected(mut self) -> anyhow::Result<()> {
-        self.do_connect_loop(false).await
+    /// Scan for access points, strongest first, and publish the result for the API.
+    async fn scan(&mut self) -> Vec<WifiScanEntry> {
+        info!("WiFi scanning...");
+        let wifi = self.wifi.as_mut().unwrap();
+        let mut seen = match Box::pin(wifi.scan()).await {
+            Ok(aps) => aps
+                .iter()
+                .map(|ap| WifiScanEntry {
+                    ssid: ap.ssid.to_string(),
+                    bssid: format_bssid(&ap.bssid),
+                    channel: ap.channel,
+                    rssi: ap.signal_strength,
+                    auth: match ap.auth_method {
+                        Some(auth) => format!("{auth:?}"),
+                        None => "Unknown".into(),
+                    },
+                })
+                .collect(),
+            Err(e) => {
+                error!("WiFi scan failed: {e:?}");
+                Vec::new()
+            }
+        };
+        seen.sort_by_key(|ap| std::cmp::Reverse(ap.rssi));
+        info!("WiFi scan found {} access points.", seen.len());
+
+        *self.state.wifi_scan.write().await = seen.clone();
+        self.state.wifi_scan_done.notify_waiters();
+        seen
     }
 
-    async fn do_connect_loop(&mut self, initial: bool) -> anyhow::Result<()> {
+    async fn connect_network(&mut self, network: &WifiNetwork) -> anyhow::Result<()> {
+        self.set_client_config(network)?;
         let wifi = self.wifi.as_mut().unwrap();
+
+        info!("WiFi connecting...");
+        tokio::time::timeout(WIFI_CONNECT_TIMEOUT, Box::pin(wifi.connect())).await??;
+
+        info!("WiFi waiting for association...");
+        Box::pin(wifi.ip_wait_while(|w| w.is_up().map(|s| !s), Some(WIFI_CONNECT_TIMEOUT))).await?;
+        Ok(())
+    }
+
+    async fn update_ip_info(&self) -> anyhow::Result<()> {
+        let netif = self.wifi.as_ref().unwrap().wifi().sta_netif();
+        let ip_info = netif.get_ip_info()?;
+        *self.state.if_index.write().await = netif.get_index();
+        *self.state.ip_addr.write().await = ip_info.ip;
+        *self.state.ping_ip.write().await = Some(ip_info.subnet.gateway);
+        Ok(())
+    }
+
+    /// Serve the config UI on our own access point until the station connects.
+    async fn access_point(&mut self) -> anyhow::Result<()> {
+        let myid = self.state.myid.read().await.clone();
+        let retry_sta = !self.state.config.read().await.wifi_networks.is_empty();
+
+        let wifi = self.wifi.as_mut().unwrap();
+        let client_cfg = match wifi.get_configuration()? {
+            Configuration::Client(c) => c,
+            _ => ClientConfiguration::default(),
+        };
+        let ap_cfg = AccessPointConfiguration {
+            ssid: myid.as_str().try_into().unwrap_or_default(),
+            auth_method: AuthMethod::None,
+            ..Default::default()
+        };
+        wifi.set_configuration(&Configuration::Mixed(client_cfg, ap_cfg))?;
+        *self.state.ap_mode.write().await = true;
+        info!("WiFi access point {myid} up at {WIFI_AP_IP}");
+
+        loop {
+            tokio::select! {
+                _ = sleep(WIFI_AP_STA_RETRY) => {}
+                _ = self.state.wifi_scan_req.notified() => {
+                    Box::pin(self.scan()).await;
+                    continue;
+                }
+            }
+            if !retry_sta {
+                continue;
+            }
+            info!("WiFi retrying station connection...");
+            match Box::pin(self.connect_best()).await {
+                Ok(_) => break,
+                Err(e) => error!("WiFi connection failed: {e:?}"),
+            }
+        }
+
+        info!("WiFi access point down.");
+        let wifi = self.wifi.as_mut().unwrap();
+        if let Configuration::Mixed(client_cfg, _) = wifi.get_configuration()? {
+            wifi.set_configuration(&Configuration::Client(client_cfg))?;
+        }
+        *self.state.ap_mode.write().await = false;
+
+        // the API server may run with the HTTP overrides of AP mode, don't keep them on the LAN
+        let config = self.state.config.read().await.clone();
+        if !self.state.safe_mode
+            && (!config.web_ui_enabled || config.http_disabled || config.http_redirect)
+        {
+            info!("Restarting to apply the configured HTTP settings.");
+            *self.state.restart.write().await = true;
+        }
+        Ok(())
+    }
+
+    /// Move to a stronger access point of the same network once the signal stays weak.
+    async fn check_roaming(&mut self, weak_since: &mut Option<Instant>) -> anyhow::Result<()> {
+        let config = self.state.config.read().await.clone();
+        let Some(ap_info) = sta_ap_info() else {
+            return Ok(());
+        };
+        if !config.wifi_roam_enabled || ap_info.rssi >= config.wifi_roam_rssi {
+            *weak_since = None;
+            return Ok(());
+        }
+        let since = *weak_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < Duration::from_secs(config.wifi_roam_period.into()) {
+            return Ok(());
+        }
+        *weak_since = None;
+
+        let ssid = String::from_utf8_lossy(&ap_info.ssid)
+            .trim_end_matches('\0')
+            .to_string();
+        let bssid = format_bssid(&ap_info.bssid);
+        // a pinned access point is kept however weak it is
+        let Some(network) = config
+            .wifi_networks
+            .iter()
+            .find(|n| n.ssid == ssid && n.bssid.is_empty())
+        else {
+            return Ok(());
+        };
+
+        let seen = Box::pin(self.scan()).await;
+        let Some(target) = roam_target(&seen, &ssid, &bssid, ap_info.rssi) else {
+            info!(
+                "WiFi signal {} dBm, no stronger access point.",
+                ap_info.rssi
+            );
+            return Ok(());
+        };
+        info!(
+            "WiFi roaming from {bssid} ({} dBm) to {} ({} dBm)...",
+            ap_info.rssi, target.bssid, target.rssi
+        );
+        let roam = WifiNetwork {
+            bssid: target.bssid.clone(),
+            channel: target.channel,
+            ..network.clone()
+        };
+        Box::pin(self.wifi.as_mut().unwrap().disconnect()).await?;
+        // on failure the disconnect is handled like any other
+        Box::pin(self.connect_network(&roam)).await?;
+        self.update_ip_info().await
+    }
+
+    /// Drop the connection on request of the connectivity watchdog.
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        let action = self.state.config.read().await.watchdog_action;
+        let wifi = self.wifi.as_mut().unwrap();
+        if action == WatchdogAction::RestartNet {
+            info!("WiFi restarting...");
+            Box::pin(wifi.stop()).await?;
+            Box::pin(wifi.start()).await?;
+        } else {
+            info!("WiFi reconnecting...");
+            Box::pin(wifi.disconnect()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn stay_connected(mut self) -> anyhow::Result<()> {
+        let mut weak_since = None;
         loop {
             // Wait for disconnect before trying to connect again.  This loop ensures
             // we stay connected and is commonly missing from trivial examples as it's
             // way too difficult to showcase the core logic of an example and have
             // a proper Wi-Fi event loop without a robust async runtime.  Fortunately, we can do it
             // now!
-            let timeout = if initial {
-                Some(Duration::from_secs(30))
-            } else {
-                None
-            };
-            Box::pin(wifi.wifi_wait(|w| w.is_up(), timeout)).await.ok();
-
-            info!("WiFi connecting...");
-            Box::pin(wifi.connect()).await.ok();
-
-            info!("WiFi waiting for association...");
-            match Box::pin(wifi.ip_wait_while(|w| w.is_up().map(|s| !s), None)).await {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("WiFi error: {e:?}");
-
-                    // only exit here if this is initial connection
-                    // otherwise, keep trying
-                    if initial {
-                        bail!(e);
+            let wifi = self.wifi.as_mut().unwrap();
+            tokio::select! {
+                _ = Box::pin(wifi.wifi_wait(|w| w.is_up(), None)) => {}
+                _ = self.state.wifi_scan_req.notified() => {
+                    Box::pin(self.scan()).await;
+                    continue;
+                }
+                _ = self.state.wifi_reset_req.notified() => {
+                    if let Err(e) = Box::pin(self.reset()).await {
+                        error!("WiFi reset failed: {e:?}");
+                    }
+                }
+                _ = sleep(WIFI_ROAM_CHECK) => {
+                    if let Err(e) = Box::pin(self.check_roaming(&mut weak_since)).await {
+                        error!("WiFi roaming failed: {e:?}");
                     }
+                    continue;
                 }
             }
+            info!("WiFi disconnected.");
 
-            info!("WiFi connected.");
-            if initial {
-                return Ok(());
+            // the next best network may be a different one
+            let mut attempt = 0;
+            while let Err(e) = Box::pin(self.connect_best()).await {
+                let delay = retry_delay(attempt);
+                attempt += 1;
+                error!("WiFi error: {e:?}, retrying in {}s", delay.as_secs());
+                sleep(delay).await;
             }
+            self.update_ip_info().await?;
         }
     }
 }
+
+/// Record of the access point that the station is associated with.
+#[cfg(target_os = "espidf")]
+pub fn sta_ap_info() -> Option<esp_idf_sys::wifi_ap_record_t> {
+    let mut ap_info = esp_idf_sys::wifi_ap_record_t::default();
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) }).ok()?;
+    Some(ap_info)
+}
+
+pub fn format_bssid(b: &[u8; 6]) -> String {
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        b[0], b[1], b[2], b[3], b[4], b[5],
+    )
+}
+
+/// Delay before reconnect attempt `attempt` (from 0), doubling up to a limit.
+fn retry_delay(attempt: u32) -> Duration {
+    WIFI_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(WIFI_RETRY_DELAY_MAX)
+}
+
+/// Name of a `wifi_err_reason_t` disconnect reason code.
+fn disconnect_reason(reason: u16) -> &'static str {
+    match reason {
+        1 => "unspecified",
+        2 => "auth_expire",
+        3 => "auth_leave",
+        4 => "disassoc_due_to_inactivity",
+        5 => "assoc_toomany",
+        6 => "class2_frame_from_nonauth_sta",
+        7 => "class3_frame_from_nonassoc_sta",
+        8 => "assoc_leave",
+        9 => "assoc_not_authed",
+        14 => "mic_failure",
+        15 => "4way_handshake_timeout",
+        16 => "group_key_update_timeout",
+        23 => "802_1x_auth_failed",
+        200 => "beacon_timeout",
+        201 => "no_ap_found",
+        202 => "auth_fail",
+        203 => "assoc_fail",
+        204 => "handshake_timeout",
+        205 => "connection_fail",
+        206 => "ap_tsf_reset",
+        207 => "roaming",
+        208 => "assoc_comeback_time_too_long",
+        209 => "sa_query_timeout",
+        210 => "no_ap_found_w_compatible_security",
+        211 => "no_ap_found_in_authmode_threshold",
+        212 => "no_ap_found_in_rssi_threshold",
+        _ => "other",
+    }
+}
+
+/// Order the saved networks for connecting, those seen in the scan first.
+pub fn rank_networks<'n>(
+    networks: &'n [WifiNetwork],
+    seen: &[WifiScanEntry],
+) -> Vec<&'n WifiNetwork> {
+    let rssi = |network: &WifiNetwork| {
+        seen.iter()
+            .filter(|ap| ap.ssid == network.ssid)
+            .filter(|ap| network.bssid.is_empty() || ap.bssid.eq_ignore_ascii_case(&network.bssid))
+            .map(|ap| ap.rssi)
+            .max()
+    };
+    let mut ranked = networks
+        .iter()
+        .filter(|n| !n.ssid.is_empty())
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|n| {
+        let rssi = rssi(n);
+        (
+            rssi.is_none(),
+            std::cmp::Reverse(n.priority),
+            std::cmp::Reverse(rssi),
+        )
+    });
+    ranked
+}
+
+/// The strongest other access point of the network, if it beats the current one clearly.
+pub fn roam_target<'s>(
+    seen: &'s [WifiScanEntry],
+    ssid: &str,
+    bssid: &str,
+    rssi: i8,
+) -> Option<&'s WifiScanEntry> {
+    seen.iter()
+        .filter(|ap| ap.ssid == ssid && ap.bssid != bssid)
+        .filter(|ap| ap.rssi >= rssi.saturating_add(WIFI_ROAM_MIN_GAIN))
+        .max_by_key(|ap| ap.rssi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seen(ssid: &str, rssi: i8) -> WifiScanEntry {
+        WifiScanEntry {
+            ssid: ssid.into(),
+            bssid: format!("00:00:00:00:00:{:02x}", rssi.unsigned_abs()),
+            channel: 1,
+            rssi,
+            auth: "WPA2Personal".into(),
+        }
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let delays: Vec<u64> = (0..8).map(|a| retry_delay(a).as_secs()).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 80, 160, 300, 300]);
+        assert_eq!(retry_delay(u32::MAX), WIFI_RETRY_DELAY_MAX);
+    }
+
+    fn ssids(ranked: Vec<&WifiNetwork>) -> Vec<&str> {
+        ranked.iter().map(|n| n.ssid.as_str()).collect()
+    }
+
+    #[test]
+    fn rank_by_priority_and_rssi() {
+        let networks = [
+            test_network("lab", "", 1),
+            test_network("warehouse", "", 1),
+            test_network("hotspot", "", 0),
+            test_network("office", "", 2),
+        ];
+        let seen = [
+            seen("hotspot", -40),
+            seen("lab", -80),
+            seen("warehouse", -60),
+            seen("lab", -70),
+        ];
+        assert_eq!(
+            ssids(rank_networks(&networks, &seen)),
+            ["warehouse", "lab", "hotspot", "office"]
+        );
+    }
+
+    #[test]
+    fn rank_hidden_by_priority() {
+        let networks = [
+            test_network("lab", "", 1),
+            test_network("", "", 5),
+            test_network("hidden", "", 3),
+        ];
+        assert_eq!(ssids(rank_networks(&networks, &[])), ["hidden", "lab"]);
+    }
+
+    #[test]
+    fn rank_pinned_bssid() {
+        let networks = [
+            WifiNetwork {
+                bssid: "00:00:00:00:00:50".into(),
+                ..test_network("lab", "", 1)
+            },
+            test_network("office", "", 1),
+        ];
+        let seen = [seen("lab", -40), seen("lab", -80), seen("office", -60)];
+        assert_eq!(ssids(rank_networks(&networks, &seen)), ["office", "lab"]);
+    }
+
+    #[test]
+    fn roam_to_stronger_ap() {
+        let seen = [
+            seen("lab", -70),
+            seen("lab", -66),
+            seen("lab", -60),
+            seen("office", -30),
+        ];
+        let current = "00:00:00:00:00:46";
+        assert_eq!(
+            roam_target(&seen, "lab", current, -70).map(|ap| ap.rssi),
+            Some(-60)
+        );
+        assert!(roam_target(&seen, "lab", current, -65).is_none());
+        assert!(roam_target(&seen[..1], "lab", current, -70).is_none());
+    }
+}
+
 // EOF